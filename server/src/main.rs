@@ -50,21 +50,33 @@ fn main() -> Result<(), Box<dyn StdError>> {
         .filter(None, LevelFilter::Trace)
         .init();
     let test_pki = Arc::new(TestPKI::new());
+    test_pki.write_ca_pem("ca.pem")?;
+    info!("CA certificate written to ca.pem, use it as the client trust store");
     let pki_clone = Arc::clone(&test_pki);
     start_cert_issuer(pki_clone);
     let private_key_file = "privatekey.pem";
 
     let certs = vec![test_pki.server_cert.der().clone()];
     let private_key = PrivateKeyDer::from_pem_file(private_key_file).unwrap();
-    let result = rustls::crypto::ring::default_provider().install_default();
+    // ML-KEM is only available with aws-lc-rs. X25519MLKEM768 comes first so that it is used
+    // whenever the client sends a share for it.
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    provider.kx_groups = vec![
+        rustls::crypto::aws_lc_rs::kx_group::X25519MLKEM768,
+        rustls::crypto::aws_lc_rs::kx_group::X25519,
+        rustls::crypto::aws_lc_rs::kx_group::SECP256R1,
+        rustls::crypto::aws_lc_rs::kx_group::SECP384R1,
+    ];
+    let result = provider.install_default();
     let roots = test_pki.roots.clone().into();
     let verifier = WebPkiClientVerifier::builder(roots)
         .allow_unknown_revocation_status()
         .build()
         .unwrap();
-    let config = rustls::ServerConfig::builder()
+    let mut config = rustls::ServerConfig::builder()
         .with_client_cert_verifier(verifier)
         .with_single_cert(certs, private_key)?;
+    config.max_early_data_size = 16384;
 
     info!("Listening on [::]:4443");
     let listener = TcpListener::bind(format!("[::]:{}", 4443)).unwrap();
@@ -112,11 +124,19 @@ fn start_cert_issuer(pki_clone: Arc<TestPKI>) {
 fn next_client(config: ServerConfig, mut stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
     let mut conn = rustls::ServerConnection::new(Arc::new(config))?;
     conn.complete_io(&mut stream)?;
+    let mut early_data = Vec::new();
+    if let Some(mut reader) = conn.early_data() {
+        reader.read_to_end(&mut early_data)?;
+    }
 
     info!("io completed, writing hello message");
     conn.writer()
         .write_all("Hello from the server\0".as_bytes())?;
     conn.complete_io(&mut stream)?;
+    if !early_data.is_empty() {
+        println!("Received early data from client: {:?}", early_data);
+        return Ok(());
+    }
     let mut buf = [0; 64];
     let len = conn.reader().read(&mut buf)?;
     println!("Received message from client: {:?}", &buf[..len]);
@@ -178,6 +198,10 @@ impl TestPKI {
         }
     }
 
+    pub fn write_ca_pem(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.ca_cert.pem())
+    }
+
     pub fn sign_csr(&self, csr: &[u8]) -> anyhow::Result<Vec<u8>> {
         let cert_req = x509_cert::request::CertReq::from_der(csr)?;
         info!("Received CSR: {:?}", cert_req);
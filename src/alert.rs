@@ -0,0 +1,70 @@
+use std::fmt::{Display, Formatter};
+use tls_parser::{TlsAlertDescription, TlsAlertSeverity};
+
+/// A handshake failure that must be reported to the peer with a fatal alert.
+#[derive(Debug)]
+pub(crate) struct FatalAlert(pub(crate) TlsAlertDescription);
+
+impl Display for FatalAlert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fatal alert {:?}", self.0)
+    }
+}
+
+impl std::error::Error for FatalAlert {}
+
+pub(crate) fn fatal<C>(description: TlsAlertDescription, context: C) -> anyhow::Error
+where
+    C: Display + Send + Sync + 'static,
+{
+    anyhow::Error::new(FatalAlert(description)).context(context)
+}
+
+pub(crate) fn alert_for(err: &anyhow::Error) -> Option<TlsAlertDescription> {
+    err.downcast_ref::<FatalAlert>().map(|alert| alert.0)
+}
+
+pub(crate) fn alert_payload(description: TlsAlertDescription) -> [u8; 2] {
+    [TlsAlertSeverity::Fatal.0, description.0]
+}
+
+pub(crate) const CLOSE_NOTIFY: [u8; 2] = [
+    TlsAlertSeverity::Warning.0,
+    TlsAlertDescription::CloseNotify.0,
+];
+
+/// An alert received from the peer. Other than `close_notify`, every alert that reaches the
+/// caller ends the connection.
+#[derive(Debug)]
+pub(crate) struct ReceivedAlert(pub(crate) TlsAlertDescription);
+
+impl Display for ReceivedAlert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "received alert {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ReceivedAlert {}
+
+pub(crate) fn is_close_notify(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ReceivedAlert>()
+        .is_some_and(|alert| alert.0 == TlsAlertDescription::CloseNotify)
+}
+
+/// Handles the content of an Alert record. `user_canceled` is only logged, since the peer
+/// follows it with `close_notify`; any other alert is returned as a [`ReceivedAlert`] error.
+/// TLS 1.3 treats every alert other than those two as fatal, whatever its level says.
+pub(crate) fn process_alert(content: &[u8]) -> anyhow::Result<()> {
+    let &[level, description] = content else {
+        return Err(fatal(
+            TlsAlertDescription::DecodeError,
+            format!("malformed alert: {:02X?}", content),
+        ));
+    };
+    let description = TlsAlertDescription(description);
+    log::info!("received alert {:?} level {}", description, level);
+    if description == TlsAlertDescription::UserCanceled {
+        return Ok(());
+    }
+    Err(anyhow::Error::new(ReceivedAlert(description)))
+}
@@ -0,0 +1,376 @@
+use crate::EarlyDataStatus;
+use crate::certificate_request::CertificateRequest;
+use crate::client_config::ClientConfig;
+use crate::client_connection::{ClientConnection, Event};
+use crate::client_identity::{AsyncClientSigner, IdentityStore};
+use log::info;
+use ring::rand::SecureRandom;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tls_parser::SignatureScheme;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Bytes read from the socket per call.
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// What the connection asked of the driver in [`Event::CertificateRequested`] or
+/// [`Event::SignatureRequired`].
+enum Request {
+    Certificate(CertificateRequest),
+    Signature(SignatureScheme, Vec<u8>),
+}
+
+type Signing = Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + Send>>;
+
+/// A client TLS connection over a tokio TCP socket, driving a [`ClientConnection`]. It
+/// behaves like [`crate::tls_stream::TlsStream`], but never blocks the executor: the socket
+/// is polled and the CertificateVerify signature is awaited. After the handshake, reading
+/// goes on while a signature for post-handshake authentication is computed.
+pub(crate) struct AsyncTlsStream<S> {
+    tcp_stream: TcpStream,
+    connection: ClientConnection,
+    identities: Arc<IdentityStore<S>>,
+    /// The identity whose signer CertificateVerify is signed with.
+    selected_identity: Option<usize>,
+    /// Requests from the connection not yet answered, oldest first.
+    requests: VecDeque<Request>,
+    /// The signature being computed for the last request taken from `requests`.
+    signing: Option<Signing>,
+    /// Decrypted application data not yet returned by `poll_read`.
+    plaintext: VecDeque<u8>,
+    /// Set once the server has sent close_notify.
+    read_closed: bool,
+    /// Bytes from the connection not yet written to the socket, from `outgoing_pos` on.
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+}
+
+impl<S: AsyncClientSigner + Send + Sync + 'static> AsyncTlsStream<S> {
+    /// Connects to `config.server_name` and runs the handshake, offering `early_data` as
+    /// described at [`ClientConnection::new`]. If the server asks for a certificate, it gets
+    /// the one [`IdentityStore::select`] picks from `identities`. That identity's signer is
+    /// awaited for the CertificateVerify signature, so it should hand the TPM operation to
+    /// another thread, as [`crate::tpm::TpmSigningThread`] does.
+    pub(crate) async fn connect_with_early_data(
+        config: Arc<ClientConfig>,
+        early_data: &[u8],
+        identities: Arc<IdentityStore<S>>,
+        rng: impl SecureRandom + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        info!("connecting to {}:{}", config.server_name, config.port);
+        let tcp_stream = TcpStream::connect((config.server_name.as_str(), config.port)).await?;
+        let connection = ClientConnection::new(config, early_data, rng)?;
+        let mut tls_stream = Self::new(tcp_stream, connection, identities);
+        while tls_stream.connection.is_handshaking() {
+            tls_stream.write_outgoing().await?;
+            let events = tls_stream.receive().await?;
+            tls_stream.on_events(events);
+            let answered = std::future::poll_fn(|cx| tls_stream.poll_answer(cx)).await;
+            tls_stream.write_outgoing().await?;
+            answered?;
+        }
+        tls_stream.write_outgoing().await?;
+        Ok(tls_stream)
+    }
+
+    fn new(
+        tcp_stream: TcpStream,
+        connection: ClientConnection,
+        identities: Arc<IdentityStore<S>>,
+    ) -> Self {
+        Self {
+            tcp_stream,
+            connection,
+            identities,
+            selected_identity: None,
+            requests: VecDeque::new(),
+            signing: None,
+            plaintext: VecDeque::new(),
+            read_closed: false,
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+        }
+    }
+
+    /// See [`ClientConnection::alpn_protocol`].
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.connection.alpn_protocol()
+    }
+
+    /// See [`ClientConnection::resumed`].
+    pub(crate) fn resumed(&self) -> bool {
+        self.connection.resumed()
+    }
+
+    /// See [`ClientConnection::early_data_status`].
+    pub(crate) fn early_data_status(&self) -> EarlyDataStatus {
+        self.connection.early_data_status()
+    }
+
+    async fn write_outgoing(&mut self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_write_outgoing(cx)).await
+    }
+
+    /// Passes one read from the socket to [`ClientConnection::receive`], writing what it
+    /// queued, the alert for an error included, before returning.
+    async fn receive(&mut self) -> anyhow::Result<Vec<Event>> {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+        let n = self.tcp_stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        let events = self.connection.receive(&buf[..n]);
+        self.write_outgoing().await?;
+        events
+    }
+
+    /// Handles `events`, queueing requests for a certificate or signature.
+    fn on_events(&mut self, events: Vec<Event>) {
+        for event in events {
+            match event {
+                Event::CertificateRequested(request) => {
+                    self.requests.push_back(Request::Certificate(request))
+                }
+                Event::SignatureRequired(scheme, input) => {
+                    self.requests.push_back(Request::Signature(scheme, input))
+                }
+                Event::HandshakeComplete | Event::UserCanceled => {}
+                Event::ApplicationData(data) => self.plaintext.extend(data),
+                Event::PeerClosed => self.read_closed = true,
+            }
+        }
+    }
+
+    /// Answers the queued requests with the selected identity, in order. Pending while a
+    /// signature is being computed.
+    fn poll_answer(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        loop {
+            let answered = if let Some(signing) = &mut self.signing {
+                let signature = ready!(signing.as_mut().poll(cx));
+                self.signing = None;
+                match signature {
+                    Ok(signature) => self.connection.provide_signature(&signature),
+                    Err(e) => Err(self.connection.abort(e)),
+                }
+            } else {
+                match self.requests.pop_front() {
+                    None => return Poll::Ready(Ok(())),
+                    Some(Request::Certificate(request)) => {
+                        self.selected_identity = self.identities.select(&request);
+                        let identity = self.selected_identity.map(|i| self.identities.identity(i));
+                        self.connection.provide_certificate(identity)
+                    }
+                    Some(Request::Signature(scheme, input)) => {
+                        let Some(index) = self.selected_identity else {
+                            return Poll::Ready(Err(self.connection.abort(anyhow::anyhow!(
+                                "signature requested without a client identity"
+                            ))));
+                        };
+                        let identities = Arc::clone(&self.identities);
+                        self.signing = Some(Box::pin(async move {
+                            identities.signer(index).sign(scheme, input).await
+                        }));
+                        continue;
+                    }
+                }
+            };
+            let queued = self.requests.len();
+            self.on_events(answered?);
+            // Answered before the requests still queued, as [`Event::SignatureRequired`] asks.
+            self.requests.rotate_right(self.requests.len() - queued);
+        }
+    }
+
+    /// Returns `err` after writing what the connection queued, the fatal alert for `err`
+    /// included, as far as the socket takes it without waiting.
+    fn poll_fail<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        err: anyhow::Error,
+    ) -> Poll<std::io::Result<T>> {
+        if let Poll::Ready(Err(e)) = self.poll_write_outgoing(cx) {
+            info!("failed to send fatal alert: {:?}", e);
+        }
+        Poll::Ready(Err(std::io::Error::other(err)))
+    }
+
+    /// Writes everything the connection has queued.
+    fn poll_write_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.outgoing.extend(self.connection.take_outgoing());
+        while self.outgoing_pos < self.outgoing.len() {
+            let pending = &self.outgoing[self.outgoing_pos..];
+            let n = ready!(Pin::new(&mut self.tcp_stream).poll_write(cx, pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += n;
+        }
+        self.outgoing.clear();
+        self.outgoing_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncClientSigner + Send + Sync + 'static> AsyncRead for AsyncTlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // A CertificateRequest after the handshake is answered alongside reading: data
+            // keeps being returned while its signature is pending.
+            if let Poll::Ready(Err(e)) = this.poll_answer(cx) {
+                return this.poll_fail(cx, e);
+            }
+            // Replies such as KeyUpdate go out as soon as the socket takes them; reading does
+            // not wait for that.
+            if let Poll::Ready(Err(e)) = this.poll_write_outgoing(cx) {
+                return Poll::Ready(Err(e));
+            }
+            if !this.plaintext.is_empty() || this.read_closed {
+                let n = buf.remaining().min(this.plaintext.len());
+                let (front, back) = this.plaintext.as_slices();
+                let from_front = n.min(front.len());
+                buf.put_slice(&front[..from_front]);
+                buf.put_slice(&back[..n - from_front]);
+                this.plaintext.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            let mut incoming = [0u8; READ_BUF_LEN];
+            let mut incoming = ReadBuf::new(&mut incoming);
+            ready!(Pin::new(&mut this.tcp_stream).poll_read(cx, &mut incoming))?;
+            if incoming.filled().is_empty() {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            match this.connection.receive(incoming.filled()) {
+                Ok(events) => this.on_events(events),
+                Err(e) => return this.poll_fail(cx, e),
+            }
+        }
+    }
+}
+
+impl<S: AsyncClientSigner + Send + Sync + 'static> AsyncWrite for AsyncTlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // Earlier data has to reach the socket before more is accepted.
+        ready!(this.poll_write_outgoing(cx))?;
+        this.connection
+            .send_application_data(buf)
+            .map_err(std::io::Error::other)?;
+        if let Poll::Ready(Err(e)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.tcp_stream).poll_flush(cx)
+    }
+
+    /// [`ClientConnection::send_close_notify`], then shuts down the write half of the socket.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.connection
+            .send_close_notify()
+            .map_err(std::io::Error::other)?;
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.tcp_stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{certificate_request, client_identity, handshake};
+    use tls_parser::{TlsAlertDescription, TlsRecordType};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// A signer whose key cannot be used.
+    struct NoSigner;
+
+    impl AsyncClientSigner for NoSigner {
+        async fn sign(&self, _: SignatureScheme, _: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("no client identity")
+        }
+    }
+
+    #[test]
+    fn read_error_sends_fatal_alert() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (mut server, connection) = handshake(usize::MAX, usize::MAX);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tcp_stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut stream = AsyncTlsStream::new(
+                tcp_stream,
+                connection,
+                Arc::new(IdentityStore::<NoSigner>::new()),
+            );
+
+            let mut record = server.seal(TlsRecordType::ApplicationData, b"hello", usize::MAX);
+            *record.last_mut().unwrap() ^= 1;
+            socket.write_all(&record).await.unwrap();
+            let mut buf = [0u8; 16];
+            stream.read(&mut buf).await.unwrap_err();
+            drop(stream);
+
+            let mut alert = Vec::new();
+            socket.read_to_end(&mut alert).await.unwrap();
+            let (content_type, content) = server.open(&alert);
+            assert_eq!(content_type, TlsRecordType::Alert);
+            assert_eq!(content, [2, TlsAlertDescription::BadRecordMac.0]);
+        });
+    }
+
+    #[test]
+    fn signer_error_sends_internal_error_alert() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (mut server, connection) = handshake(usize::MAX, usize::MAX);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tcp_stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut identities = IdentityStore::new();
+            identities.add(client_identity().0, NoSigner);
+            let mut stream = AsyncTlsStream::new(tcp_stream, connection, Arc::new(identities));
+
+            let request = certificate_request(&[1], &[]);
+            let record = server.seal(TlsRecordType::Handshake, &request, usize::MAX);
+            socket.write_all(&record).await.unwrap();
+            let mut buf = [0u8; 16];
+            stream.read(&mut buf).await.unwrap_err();
+            drop(stream);
+
+            let mut alert = Vec::new();
+            socket.read_to_end(&mut alert).await.unwrap();
+            let (content_type, content) = server.open(&alert);
+            assert_eq!(content_type, TlsRecordType::Alert);
+            assert_eq!(content, [2, TlsAlertDescription::InternalError.0]);
+        });
+    }
+}
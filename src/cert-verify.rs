@@ -0,0 +1,171 @@
+use crate::alert;
+use der::Decode;
+use der::oid::ObjectIdentifier;
+use der::oid::db::rfc5912::{ID_EC_PUBLIC_KEY, RSA_ENCRYPTION};
+use der::oid::db::rfc8410::ID_ED_25519;
+use log::{debug, info};
+use ring::signature;
+use tls_parser::{SignatureScheme, TlsAlertDescription};
+
+const SERVER_CONTEXT_STRING: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+const CLIENT_CONTEXT_STRING: &[u8] = b"TLS 1.3, client CertificateVerify\0";
+
+/// Schemes advertised in `signature_algorithms` and accepted in the server's CertificateVerify.
+pub(crate) const SUPPORTED_SIGNATURE_SCHEMES: [SignatureScheme; 6] = [
+    SignatureScheme::rsa_pss_rsae_sha256,
+    SignatureScheme::rsa_pss_rsae_sha384,
+    SignatureScheme::rsa_pss_rsae_sha512,
+    SignatureScheme::ecdsa_secp256r1_sha256,
+    SignatureScheme::ecdsa_secp384r1_sha384,
+    SignatureScheme::ed25519,
+];
+
+fn verification_algorithm(
+    scheme: SignatureScheme,
+) -> Option<(
+    &'static dyn signature::VerificationAlgorithm,
+    ObjectIdentifier,
+)> {
+    match scheme {
+        SignatureScheme::rsa_pss_rsae_sha256 => {
+            Some((&signature::RSA_PSS_2048_8192_SHA256, RSA_ENCRYPTION))
+        }
+        SignatureScheme::rsa_pss_rsae_sha384 => {
+            Some((&signature::RSA_PSS_2048_8192_SHA384, RSA_ENCRYPTION))
+        }
+        SignatureScheme::rsa_pss_rsae_sha512 => {
+            Some((&signature::RSA_PSS_2048_8192_SHA512, RSA_ENCRYPTION))
+        }
+        SignatureScheme::ecdsa_secp256r1_sha256 => {
+            Some((&signature::ECDSA_P256_SHA256_ASN1, ID_EC_PUBLIC_KEY))
+        }
+        SignatureScheme::ecdsa_secp384r1_sha384 => {
+            Some((&signature::ECDSA_P384_SHA384_ASN1, ID_EC_PUBLIC_KEY))
+        }
+        SignatureScheme::ed25519 => Some((&signature::ED25519, ID_ED_25519)),
+        _ => None,
+    }
+}
+
+/// Schemes the TPM's RSA key can sign our CertificateVerify with, most preferred first.
+/// SHA-256 leads since every TPM 2.0 implements it.
+pub(crate) const TPM_SIGNATURE_SCHEMES: [SignatureScheme; 3] = [
+    SignatureScheme::rsa_pss_rsae_sha256,
+    SignatureScheme::rsa_pss_rsae_sha384,
+    SignatureScheme::rsa_pss_rsae_sha512,
+];
+
+/// Picks the scheme for our CertificateVerify: the first in the server's CertificateRequest
+/// signature_algorithms, which lists them in its order of preference, that our key signs
+/// with.
+pub(crate) fn select_client_signature_scheme(
+    accepted_by_server: &[SignatureScheme],
+    ours: &[SignatureScheme],
+) -> anyhow::Result<SignatureScheme> {
+    let scheme = accepted_by_server
+        .iter()
+        .find(|scheme| ours.contains(scheme))
+        .ok_or_else(|| {
+            alert::fatal(
+                TlsAlertDescription::HandshakeFailure,
+                format!(
+                    "server accepts none of our signature schemes: {:?}",
+                    accepted_by_server
+                ),
+            )
+        })?;
+    info!("client CertificateVerify will use {:?}", scheme);
+    Ok(*scheme)
+}
+
+pub(crate) fn client_signing_input(transcript_hash: &[u8]) -> Vec<u8> {
+    [&[0x20; 64], CLIENT_CONTEXT_STRING, transcript_hash].concat()
+}
+
+pub(crate) fn server_signing_input(transcript_hash: &[u8]) -> Vec<u8> {
+    [&[0x20; 64], SERVER_CONTEXT_STRING, transcript_hash].concat()
+}
+
+/// Checks the server's CertificateVerify signature over the transcript hash
+/// taken right after the server Certificate message.
+pub(crate) fn verify_server_signature(
+    leaf_cert: &[u8],
+    scheme: SignatureScheme,
+    signature: &[u8],
+    transcript_hash: &[u8],
+) -> anyhow::Result<()> {
+    if !SUPPORTED_SIGNATURE_SCHEMES.contains(&scheme) {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            format!(
+                "server used a signature scheme we did not offer: {:?}",
+                scheme
+            ),
+        ));
+    }
+    let (algorithm, key_oid) = verification_algorithm(scheme).ok_or(anyhow::anyhow!(
+        "no verification algorithm for {:?}",
+        scheme
+    ))?;
+    let cert = x509_cert::certificate::Certificate::from_der(leaf_cert)
+        .map_err(|e| alert::fatal(TlsAlertDescription::BadCertificate, e))?;
+    let spki = &cert.tbs_certificate.subject_public_key_info;
+    if spki.algorithm.oid != key_oid {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            format!(
+                "signature scheme {:?} does not match certificate key {}",
+                scheme, spki.algorithm.oid
+            ),
+        ));
+    }
+    let signing_input = server_signing_input(transcript_hash);
+    debug!(
+        "verifying server signature scheme: {:?} signing_input: {:02X?}",
+        scheme, signing_input
+    );
+    signature::UnparsedPublicKey::new(algorithm, spki.subject_public_key.raw_bytes())
+        .verify(&signing_input, signature)
+        .map_err(|_| {
+            alert::fatal(
+                TlsAlertDescription::DecryptError,
+                "server CertificateVerify signature mismatch",
+            )
+        })?;
+    info!("server CertificateVerify verified with {:?}", scheme);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_signature_scheme_follows_server_preference() {
+        let scheme = select_client_signature_scheme(
+            &[
+                SignatureScheme::ed25519,
+                SignatureScheme::rsa_pss_rsae_sha384,
+                SignatureScheme::rsa_pss_rsae_sha256,
+            ],
+            &TPM_SIGNATURE_SCHEMES,
+        );
+        assert_eq!(scheme.unwrap(), SignatureScheme::rsa_pss_rsae_sha384);
+    }
+
+    #[test]
+    fn no_common_client_signature_scheme_is_handshake_failure() {
+        let err = select_client_signature_scheme(
+            &[
+                SignatureScheme::ecdsa_secp256r1_sha256,
+                SignatureScheme::ed25519,
+            ],
+            &TPM_SIGNATURE_SCHEMES,
+        )
+        .unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::HandshakeFailure)
+        );
+    }
+}
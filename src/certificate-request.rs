@@ -0,0 +1,141 @@
+use crate::alert;
+use crate::codec;
+use tls_parser::nom;
+use tls_parser::nom::multi::length_data;
+use tls_parser::nom::number::complete::{be_u8, be_u16};
+use tls_parser::{SignatureScheme, TlsAlertDescription, TlsExtensionType};
+
+const CERTIFICATE_REQUEST: u8 = 13;
+
+/// What the server asked of our certificate in a CertificateRequest (RFC 8446, section 4.3.2).
+#[derive(Clone, Debug)]
+pub(crate) struct CertificateRequest {
+    /// Echoed in our Certificate. Empty during the handshake.
+    pub(crate) context: Vec<u8>,
+    /// Schemes the server accepts in our CertificateVerify, in its order of preference.
+    pub(crate) signature_schemes: Vec<SignatureScheme>,
+    /// DER-encoded distinguished names of the CAs the server accepts; empty for any.
+    pub(crate) certificate_authorities: Vec<Vec<u8>>,
+    /// Extensions our certificate must carry; empty for no constraints.
+    pub(crate) oid_filters: Vec<OidFilter>,
+    /// Types of all extensions in the request. Our CertificateEntry extensions answer these
+    /// and may not include any other.
+    pub(crate) extension_types: Vec<TlsExtensionType>,
+}
+
+/// One entry of the oid_filters extension (RFC 8446, section 4.2.5).
+#[derive(Clone, Debug)]
+pub(crate) struct OidFilter {
+    /// The extension's OID, without DER tag and length.
+    pub(crate) oid: Vec<u8>,
+    /// The DER-encoded extension value whose contents our certificate must include; empty
+    /// when the extension just has to be present.
+    pub(crate) values: Vec<u8>,
+}
+
+pub(crate) fn is_certificate_request(msg: &[u8]) -> bool {
+    msg.first() == Some(&CERTIFICATE_REQUEST)
+}
+
+fn parse_signature_schemes(i: &[u8]) -> nom::IResult<&[u8], Vec<SignatureScheme>> {
+    let (rest, mut i) = length_data(be_u16)(i)?;
+    let mut schemes = Vec::new();
+    while !i.is_empty() {
+        let (next, scheme) = be_u16(i)?;
+        schemes.push(SignatureScheme(scheme));
+        i = next;
+    }
+    Ok((rest, schemes))
+}
+
+fn parse_certificate_authorities(i: &[u8]) -> nom::IResult<&[u8], Vec<Vec<u8>>> {
+    let (rest, mut i) = length_data(be_u16)(i)?;
+    let mut names = Vec::new();
+    while !i.is_empty() {
+        let (next, name) = length_data(be_u16)(i)?;
+        names.push(name.to_vec());
+        i = next;
+    }
+    Ok((rest, names))
+}
+
+fn parse_oid_filters(i: &[u8]) -> nom::IResult<&[u8], Vec<OidFilter>> {
+    let (rest, mut i) = length_data(be_u16)(i)?;
+    let mut filters = Vec::new();
+    while !i.is_empty() {
+        let (next, oid) = length_data(be_u8)(i)?;
+        let (next, values) = length_data(be_u16)(next)?;
+        filters.push(OidFilter {
+            oid: oid.to_vec(),
+            values: values.to_vec(),
+        });
+        i = next;
+    }
+    Ok((rest, filters))
+}
+
+pub(crate) fn parse_certificate_request(msg: &[u8]) -> anyhow::Result<CertificateRequest> {
+    let (msg_type, body) = codec::split_handshake(msg)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    if msg_type != CERTIFICATE_REQUEST {
+        anyhow::bail!(
+            "expected CertificateRequest, got handshake type {}",
+            msg_type
+        );
+    }
+    let (i, context) = length_data(be_u8)(body).map_err(|e: nom::Err<nom::error::Error<_>>| {
+        alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e))
+    })?;
+    let extensions = codec::parse_extensions(i)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    let signature_algorithms =
+        codec::find_extension(&extensions, TlsExtensionType::SignatureAlgorithms).ok_or_else(
+            || {
+                alert::fatal(
+                    TlsAlertDescription::MissingExtension,
+                    "CertificateRequest without signature_algorithms",
+                )
+            },
+        )?;
+    let signature_schemes = match parse_signature_schemes(signature_algorithms) {
+        Ok((rest, schemes)) if rest.is_empty() && !schemes.is_empty() => schemes,
+        _ => {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                "malformed CertificateRequest signature_algorithms",
+            ));
+        }
+    };
+    let certificate_authorities =
+        match codec::find_extension(&extensions, TlsExtensionType::CertificateAuthorities) {
+            Some(data) => match parse_certificate_authorities(data) {
+                Ok((rest, names)) if rest.is_empty() && !names.is_empty() => names,
+                _ => {
+                    return Err(alert::fatal(
+                        TlsAlertDescription::DecodeError,
+                        "malformed CertificateRequest certificate_authorities",
+                    ));
+                }
+            },
+            None => Vec::new(),
+        };
+    let oid_filters = match codec::find_extension(&extensions, TlsExtensionType::OidFilters) {
+        Some(data) => match parse_oid_filters(data) {
+            Ok(([], filters)) => filters,
+            _ => {
+                return Err(alert::fatal(
+                    TlsAlertDescription::DecodeError,
+                    "malformed CertificateRequest oid_filters",
+                ));
+            }
+        },
+        None => Vec::new(),
+    };
+    Ok(CertificateRequest {
+        context: context.to_vec(),
+        signature_schemes,
+        certificate_authorities,
+        oid_filters,
+        extension_types: extensions.iter().map(|ext| ext.ext_type).collect(),
+    })
+}
@@ -0,0 +1,116 @@
+use crate::alert;
+use ring::{aead, digest, hkdf, hmac};
+use tls_parser::{
+    TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256,
+    TlsAlertDescription,
+};
+
+/// The algorithms a TLS 1.3 cipher suite fixes for the key schedule and record protection.
+pub(crate) struct CipherSuite {
+    pub(crate) id: u16,
+    pub(crate) hash: &'static digest::Algorithm,
+    pub(crate) hkdf: &'static hkdf::Algorithm,
+    pub(crate) hmac: &'static hmac::Algorithm,
+    pub(crate) aead: &'static aead::Algorithm,
+    /// Records that may be protected under one key before it must be updated
+    /// (RFC 8446, section 5.5).
+    pub(crate) confidentiality_limit: u64,
+}
+
+impl CipherSuite {
+    pub fn hash_len(&self) -> usize {
+        self.hash.output_len()
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.aead.key_len()
+    }
+
+    pub fn iv_len(&self) -> usize {
+        aead::NONCE_LEN
+    }
+}
+
+impl std::fmt::Debug for CipherSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CipherSuite({:#06X})", self.id)
+    }
+}
+
+/// 2^24.5 full-size records.
+const AES_GCM_CONFIDENTIALITY_LIMIT: u64 = 23_726_566;
+
+pub(crate) static TLS13_AES_128_GCM_SHA256: CipherSuite = CipherSuite {
+    id: TLS_AES_128_GCM_SHA256,
+    hash: &digest::SHA256,
+    hkdf: &hkdf::HKDF_SHA256,
+    hmac: &hmac::HMAC_SHA256,
+    aead: &aead::AES_128_GCM,
+    confidentiality_limit: AES_GCM_CONFIDENTIALITY_LIMIT,
+};
+
+pub(crate) static TLS13_AES_256_GCM_SHA384: CipherSuite = CipherSuite {
+    id: TLS_AES_256_GCM_SHA384,
+    hash: &digest::SHA384,
+    hkdf: &hkdf::HKDF_SHA384,
+    hmac: &hmac::HMAC_SHA384,
+    aead: &aead::AES_256_GCM,
+    confidentiality_limit: AES_GCM_CONFIDENTIALITY_LIMIT,
+};
+
+pub(crate) static TLS13_CHACHA20_POLY1305_SHA256: CipherSuite = CipherSuite {
+    id: TLS_CHACHA20_POLY1305_SHA256,
+    hash: &digest::SHA256,
+    hkdf: &hkdf::HKDF_SHA256,
+    hmac: &hmac::HMAC_SHA256,
+    aead: &aead::CHACHA20_POLY1305,
+    // The sequence number runs out long before the ChaCha20-Poly1305 limits are reached.
+    confidentiality_limit: u64::MAX,
+};
+
+pub(crate) static SUPPORTED_CIPHER_SUITES: [&CipherSuite; 3] = [
+    &TLS13_AES_128_GCM_SHA256,
+    &TLS13_AES_256_GCM_SHA384,
+    &TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+/// Cipher suites offered in the ClientHello, in order of preference. ChaCha20-Poly1305 moves
+/// to the front on CPUs without AES instructions, where software AES-GCM is slow.
+pub(crate) fn preferred_cipher_suites() -> Vec<&'static CipherSuite> {
+    let mut suites = SUPPORTED_CIPHER_SUITES.to_vec();
+    if !has_aes_hardware() {
+        suites.sort_by_key(|suite| suite.id != TLS_CHACHA20_POLY1305_SHA256);
+    }
+    suites
+}
+
+fn has_aes_hardware() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+pub(crate) fn find(id: u16) -> Option<&'static CipherSuite> {
+    SUPPORTED_CIPHER_SUITES
+        .iter()
+        .find(|suite| suite.id == id)
+        .copied()
+}
+
+/// Looks up the suite selected by the server, which must be one we offered.
+pub(crate) fn negotiate(id: u16) -> anyhow::Result<&'static CipherSuite> {
+    find(id).ok_or(alert::fatal(
+        TlsAlertDescription::IllegalParameter,
+        format!("server selected a cipher suite we did not offer: {:#06X}", id),
+    ))
+}
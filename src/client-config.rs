@@ -0,0 +1,106 @@
+use crate::key_share;
+use crate::resumption::{self, SessionCache};
+use crate::trust_store::TrustStore;
+use std::net::IpAddr;
+use tls_parser::NamedGroup;
+
+pub(crate) const DEFAULT_PORT: u16 = 4443;
+
+/// Per-connection settings for the client handshake.
+pub(crate) struct ClientConfig {
+    /// The host we connect to: a DNS name or an IP literal. DNS names are sent as SNI and
+    /// both kinds are checked against the server certificate's subjectAltName.
+    pub(crate) server_name: String,
+    pub(crate) port: u16,
+    pub(crate) trust_store: TrustStore,
+    pub(crate) middlebox_compatibility_mode: bool,
+    /// ALPN protocol names to offer, most preferred first. Nothing is offered when empty.
+    pub(crate) alpn_protocols: Vec<Vec<u8>>,
+    /// Session tickets received on earlier connections, offered to resume without the TPM.
+    pub(crate) session_cache: SessionCache,
+    /// Modes sent in psk_key_exchange_modes. psk_dhe_ke keeps forward secrecy; adding psk_ke
+    /// lets the server skip the key exchange too.
+    pub(crate) psk_key_exchange_modes: Vec<u8>,
+    /// Records sent or received under one key before it is updated with KeyUpdate. The
+    /// AEAD's usage limit applies when this is `None` or larger.
+    pub(crate) key_update_interval: Option<u64>,
+    /// Groups sent in supported_groups, most preferred first.
+    pub(crate) groups: Vec<NamedGroup>,
+    /// How many of the first `groups` get a key share in the ClientHello. More shares make
+    /// a HelloRetryRequest less likely at the cost of a larger ClientHello. The default of
+    /// two covers X25519MLKEM768 and, for servers without it, X25519.
+    pub(crate) key_shares: usize,
+    /// Whether to send post_handshake_auth, letting the server ask for a certificate after
+    /// the handshake.
+    pub(crate) post_handshake_auth: bool,
+}
+
+impl ClientConfig {
+    pub(crate) fn new(server_name: &str, trust_store: TrustStore) -> anyhow::Result<Self> {
+        // Accept "[::1]" as well as "::1" for IPv6 literals.
+        let server_name = server_name
+            .strip_prefix('[')
+            .and_then(|name| name.strip_suffix(']'))
+            .unwrap_or(server_name);
+        if server_name.parse::<IpAddr>().is_err() {
+            validate_dns_name(server_name)?;
+        }
+        Ok(Self {
+            server_name: server_name.to_ascii_lowercase(),
+            port: DEFAULT_PORT,
+            trust_store,
+            middlebox_compatibility_mode: true,
+            alpn_protocols: Vec::new(),
+            session_cache: SessionCache::default(),
+            psk_key_exchange_modes: vec![resumption::PSK_DHE_KE],
+            key_update_interval: None,
+            groups: key_share::SUPPORTED_GROUPS.to_vec(),
+            key_shares: 2,
+            post_handshake_auth: true,
+        })
+    }
+
+    /// The groups to send key shares for.
+    pub(crate) fn key_share_groups(&self) -> anyhow::Result<&[NamedGroup]> {
+        if self.groups.is_empty() {
+            anyhow::bail!("no groups configured");
+        }
+        if let Some(group) = self
+            .groups
+            .iter()
+            .find(|group| !key_share::SUPPORTED_GROUPS.contains(group))
+        {
+            anyhow::bail!("unsupported group {:?}", group);
+        }
+        Ok(&self.groups[..self.key_shares.clamp(1, self.groups.len())])
+    }
+
+    /// The name to send in the server_name extension. RFC 6066 does not allow IP literals
+    /// in SNI, so there is none for them. The trailing dot of an absolute name is dropped.
+    pub(crate) fn sni_host_name(&self) -> Option<&str> {
+        match self.server_name.parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(self.server_name.trim_end_matches('.')),
+        }
+    }
+}
+
+fn validate_dns_name(name: &str) -> anyhow::Result<()> {
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    if labels.is_empty() || labels.len() > 253 {
+        anyhow::bail!("invalid server name length: {:?}", name);
+    }
+    for label in labels.split('.') {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            anyhow::bail!("invalid label {:?} in server name {:?}", label, name);
+        }
+    }
+    Ok(())
+}
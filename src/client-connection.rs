@@ -0,0 +1,1280 @@
+use crate::certificate_request::CertificateRequest;
+use crate::client_config::ClientConfig;
+use crate::client_identity::ClientIdentity;
+use crate::enc_dec::TlsEncryptDecrypt;
+use crate::encrypted_extensions::EncryptedExtensions;
+use crate::handshake_buffer::HandshakeBuffer;
+use crate::key_schedule::{ApplicationKeySchedule, EarlyKeySchedule, HandshakeKeySchedule};
+use crate::post_handshake_auth::PostHandshakeAuth;
+use crate::resumption::SessionTicket;
+use crate::{
+    EarlyDataStatus, HelloRandom, KEY_UPDATE, PostHandshakeState, TLSRecordReader, alert,
+    cert_verify, certificate_request, hello_retry,
+};
+use log::{debug, info};
+use ring::rand::SecureRandom;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tls_parser::{SignatureScheme, TlsAlertDescription, TlsRecordType};
+
+/// What the server's bytes amounted to, in the order it sent them.
+#[derive(Debug)]
+pub(crate) enum Event {
+    /// The server asked for a client certificate; the identity to answer with goes to
+    /// [`ClientConnection::provide_certificate`]. During the handshake nothing else is
+    /// processed until then. After it records keep being processed, and requests are
+    /// answered in the order they were reported.
+    CertificateRequested(CertificateRequest),
+    /// The client CertificateVerify needs this signed with the client key, using the scheme
+    /// negotiated with the server. The signature goes to
+    /// [`ClientConnection::provide_signature`], before the next request is answered. During
+    /// the handshake nothing else is processed until then.
+    SignatureRequired(SignatureScheme, Vec<u8>),
+    HandshakeComplete,
+    ApplicationData(Vec<u8>),
+    /// The server sent user_canceled, the one alert besides close_notify that is not fatal.
+    /// Fatal ones are returned as errors.
+    UserCanceled,
+    /// The server sent close_notify and will send no more data.
+    PeerClosed,
+}
+
+/// The next message the handshake expects from the server.
+#[derive(PartialEq)]
+enum Expect {
+    ServerHello,
+    /// The ServerHello following our reply to a HelloRetryRequest.
+    ServerHelloAfterRetry,
+    EncryptedExtensions,
+    CertificateOrCertificateRequest,
+    Certificate,
+    CertificateVerify { leaf_cert: Vec<u8> },
+    Finished,
+    /// Nothing more: the server's flight is complete and ours is being sent.
+    ClientFlight,
+    /// Nothing while the driver chooses the identity to answer a CertificateRequest with.
+    ClientCertificate,
+    /// Nothing while the driver signs our CertificateVerify with `scheme`.
+    Signature { scheme: SignatureScheme },
+}
+
+struct Handshake {
+    key_schedule: HandshakeKeySchedule,
+    expect: Expect,
+    hello_random: HelloRandom,
+    /// Generates the key share for the group a HelloRetryRequest asks for.
+    rng: Box<dyn SecureRandom + Send + Sync>,
+    ticket: Option<SessionTicket>,
+    /// Whether the server sent a HelloRetryRequest.
+    retried: bool,
+    /// Set once we have sent 0-RTT data under these keys.
+    early_key_schedule: Option<EarlyKeySchedule>,
+    encrypted_extensions: EncryptedExtensions,
+    /// Set when the server asked for a certificate.
+    certificate_request: Option<CertificateRequest>,
+}
+
+enum State {
+    Handshaking(Box<Handshake>),
+    Connected(Box<ApplicationKeySchedule>),
+    /// A fatal error ended the connection.
+    Closed,
+}
+
+/// A TLS 1.3 client connection that does no I/O of its own. Bytes from the server go in
+/// through [`ClientConnection::receive`], which reports what they contained; bytes for the
+/// server collect until [`ClientConnection::take_outgoing`].
+pub(crate) struct ClientConnection {
+    config: Arc<ClientConfig>,
+    state: State,
+    tls_record_reader: TLSRecordReader,
+    outgoing: Vec<u8>,
+    post_handshake: PostHandshakeState,
+    /// Answers to the CertificateRequests received after the handshake, oldest first.
+    post_handshake_auth: VecDeque<PostHandshakeAuth>,
+    resumed: bool,
+    early_data_status: EarlyDataStatus,
+    /// Records sent or received under one key before we update it.
+    key_update_interval: u64,
+    /// Set once the server has sent close_notify.
+    read_closed: bool,
+}
+
+impl ClientConnection {
+    /// Starts a handshake with `config.server_name`; the ClientHello, and `early_data` if a
+    /// ticket allows sending it as 0-RTT data, are ready to be sent straight away. Only pass
+    /// early data that is safe to replay.
+    ///
+    /// All the randomness in our ClientHellos, the key shares included, comes from `rng`.
+    /// That is the system RNG in production; tests can pass a fixed one to get the same
+    /// ClientHello on every run.
+    pub(crate) fn new(
+        config: Arc<ClientConfig>,
+        early_data: &[u8],
+        rng: impl SecureRandom + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        let mut key_schedule = HandshakeKeySchedule::new(config.key_share_groups()?, &rng)?;
+        let hello_random = HelloRandom::generate(&rng, config.middlebox_compatibility_mode)?;
+        let ticket = config.session_cache.take(&config.server_name);
+        if let Some(ticket) = &ticket {
+            info!("offering session ticket for {}", config.server_name);
+            key_schedule.offer_psk(ticket);
+        }
+        let offer_early_data = ticket.as_ref().is_some_and(|ticket| {
+            ticket.allows_early_data(early_data.len(), &config.alpn_protocols)
+        });
+        let mut outgoing = Vec::new();
+        crate::send_client_hello(
+            &mut outgoing,
+            &mut key_schedule,
+            &config,
+            &hello_random,
+            ticket.as_ref(),
+            offer_early_data,
+            None,
+        )?;
+        let mut early_key_schedule = None;
+        if offer_early_data {
+            if hello_random.session_id.is_some() {
+                crate::send_change_cipher_spec(&mut outgoing)?;
+            }
+            let mut early = key_schedule.early_key_schedule()?;
+            info!("sending {} bytes of early data", early_data.len());
+            crate::send_application_data(&mut outgoing, &mut early, early_data)?;
+            early_key_schedule = Some(early);
+        }
+        let post_handshake = PostHandshakeState {
+            server_name: config.server_name.clone(),
+            alpn_protocol: None,
+            session_cache: config.session_cache.clone(),
+            key_update_requested: false,
+            write_closed: false,
+        };
+        Ok(Self {
+            config,
+            state: State::Handshaking(Box::new(Handshake {
+                key_schedule,
+                expect: Expect::ServerHello,
+                hello_random,
+                rng: Box::new(rng),
+                ticket,
+                retried: false,
+                early_key_schedule,
+                encrypted_extensions: EncryptedExtensions::default(),
+                certificate_request: None,
+            })),
+            tls_record_reader: TLSRecordReader::default(),
+            outgoing,
+            post_handshake,
+            post_handshake_auth: VecDeque::new(),
+            resumed: false,
+            early_data_status: EarlyDataStatus::NotSent,
+            key_update_interval: u64::MAX,
+            read_closed: false,
+        })
+    }
+
+    pub(crate) fn is_handshaking(&self) -> bool {
+        matches!(self.state, State::Handshaking(_))
+    }
+
+    /// The protocol the server selected with ALPN, if any.
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.post_handshake.alpn_protocol.as_deref()
+    }
+
+    /// Whether the handshake resumed an earlier session from a ticket, skipping certificate
+    /// authentication and with it the TPM.
+    pub(crate) fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// What became of the `early_data` passed to [`ClientConnection::new`]. Unless it was
+    /// accepted, it has to be sent again as application data.
+    pub(crate) fn early_data_status(&self) -> EarlyDataStatus {
+        self.early_data_status
+    }
+
+    /// Takes the bytes waiting to be sent to the server.
+    pub(crate) fn take_outgoing(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Processes bytes received from the server. On error the fatal alert it calls for, if
+    /// any, is queued for sending and the connection is closed.
+    pub(crate) fn receive(&mut self, data: &[u8]) -> anyhow::Result<Vec<Event>> {
+        self.tls_record_reader.push(data);
+        self.run(Self::process_records)
+    }
+
+    /// Sends the Certificate asked for by [`Event::CertificateRequested`]: `identity`'s chain,
+    /// or an empty one without an identity. The server decides whether to go on without a
+    /// certificate. Then processes any records that arrived meanwhile.
+    pub(crate) fn provide_certificate(
+        &mut self,
+        identity: Option<&ClientIdentity>,
+    ) -> anyhow::Result<Vec<Event>> {
+        self.run(|conn, events| {
+            if conn.is_handshaking() {
+                conn.send_client_certificate(identity, events)?;
+            } else {
+                conn.answer_certificate_request(identity, events)?;
+            }
+            conn.process_records(events)
+        })
+    }
+
+    /// Completes the client flight, or the answer to a CertificateRequest received after the
+    /// handshake, with the signature asked for by [`Event::SignatureRequired`]. Then
+    /// processes any records that arrived meanwhile.
+    pub(crate) fn provide_signature(&mut self, signature: &[u8]) -> anyhow::Result<Vec<Event>> {
+        self.run(|conn, events| {
+            if conn.is_handshaking() {
+                conn.finish_handshake(Some(signature), events)?;
+            } else {
+                let Some(auth) = conn.post_handshake_auth.front_mut() else {
+                    anyhow::bail!("unexpected CertificateVerify signature");
+                };
+                auth.add_certificate_verify(signature)?;
+                conn.send_post_handshake_auth()?;
+            }
+            conn.process_records(events)
+        })
+    }
+
+    /// Ends the connection for a failure on the driver's side, such as a signer that could
+    /// not sign: an internal_error alert is queued for sending and `err` returned.
+    pub(crate) fn abort(&mut self, err: anyhow::Error) -> anyhow::Error {
+        self.fail(err.context(alert::FatalAlert(TlsAlertDescription::InternalError)))
+    }
+
+    /// Encrypts `data` as application data, updating our keys first if they are due.
+    pub(crate) fn send_application_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.post_handshake.write_closed {
+            anyhow::bail!("TLS connection closed for writing");
+        }
+        self.update_keys_if_due()?;
+        let State::Connected(key_schedule) = &mut self.state else {
+            anyhow::bail!("TLS connection not established");
+        };
+        crate::send_application_data(&mut self.outgoing, key_schedule.as_mut(), data)
+    }
+
+    /// Sends a KeyUpdate and switches to new write keys. With `request_peer` the server is
+    /// asked to update its write keys too.
+    pub(crate) fn update_keys(&mut self, request_peer: bool) -> anyhow::Result<()> {
+        if self.post_handshake.write_closed {
+            anyhow::bail!("TLS connection closed for writing");
+        }
+        let State::Connected(key_schedule) = &mut self.state else {
+            anyhow::bail!("TLS connection not established");
+        };
+        crate::send_key_update(&mut self.outgoing, key_schedule, request_peer)?;
+        if request_peer {
+            self.post_handshake.key_update_requested = true;
+        }
+        Ok(())
+    }
+
+    /// Sends close_notify. Reading can continue until the server sends its own.
+    pub(crate) fn send_close_notify(&mut self) -> anyhow::Result<()> {
+        let State::Connected(key_schedule) = &mut self.state else {
+            anyhow::bail!("TLS connection not established");
+        };
+        if !self.post_handshake.write_closed {
+            self.post_handshake.write_closed = true;
+            crate::send_close_notify(&mut self.outgoing, key_schedule.as_mut())?;
+        }
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        step: impl FnOnce(&mut Self, &mut Vec<Event>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Vec<Event>> {
+        if let State::Closed = self.state {
+            anyhow::bail!("TLS connection closed");
+        }
+        let mut events = Vec::new();
+        match step(self, &mut events).and_then(|_| self.update_keys_if_due()) {
+            Ok(()) => Ok(events),
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+
+    /// Queues the fatal alert `err` calls for, if any, and closes the connection.
+    fn fail(&mut self, err: anyhow::Error) -> anyhow::Error {
+        let sent = match &mut self.state {
+            State::Handshaking(handshake) if handshake.expects_plaintext() => {
+                crate::send_plaintext_alert(&mut self.outgoing, &err)
+            }
+            State::Handshaking(handshake) => {
+                crate::send_fatal_alert(&mut self.outgoing, &mut handshake.key_schedule, &err)
+            }
+            State::Connected(key_schedule) if !self.post_handshake.write_closed => {
+                crate::send_fatal_alert(&mut self.outgoing, key_schedule.as_mut(), &err)
+            }
+            State::Connected(_) | State::Closed => Ok(()),
+        };
+        if let Err(e) = sent {
+            info!("failed to send fatal alert: {:?}", e);
+        }
+        self.state = State::Closed;
+        self.read_closed = true;
+        self.post_handshake.write_closed = true;
+        err
+    }
+
+    fn process_records(&mut self, events: &mut Vec<Event>) -> anyhow::Result<()> {
+        loop {
+            if matches!(&self.state, State::Handshaking(handshake) if handshake.awaits_driver()) {
+                // Later records may already be under the application keys.
+                return Ok(());
+            }
+            let before_server_finished = matches!(
+                &self.state,
+                State::Handshaking(handshake) if handshake.before_server_finished()
+            );
+            let Some((hdr, hdr_buf, content)) =
+                self.tls_record_reader.next_record(before_server_finished)?
+            else {
+                return Ok(());
+            };
+            if self.read_closed {
+                debug!("ignoring {:?} record after close_notify", hdr.record_type);
+                continue;
+            }
+            let server_finished = match &mut self.state {
+                State::Handshaking(handshake) => {
+                    let (content_type, content) = if handshake.expects_plaintext() {
+                        (hdr.record_type, content)
+                    } else {
+                        crate::decrypt_record(&mut handshake.key_schedule, &hdr, hdr_buf, content)?
+                    };
+                    match content_type {
+                        TlsRecordType::Handshake => {
+                            self.tls_record_reader.handshake_buffer.push(&content);
+                            handshake.process_messages(
+                                &mut self.tls_record_reader.handshake_buffer,
+                                &self.config,
+                                &mut self.outgoing,
+                            )?
+                        }
+                        TlsRecordType::Alert => {
+                            alert::process_alert(&content)?;
+                            events.push(Event::UserCanceled);
+                            false
+                        }
+                        _ => {
+                            return Err(alert::fatal(
+                                TlsAlertDescription::UnexpectedMessage,
+                                format!("expected a Handshake record, got {:?}", content_type),
+                            ));
+                        }
+                    }
+                }
+                State::Connected(key_schedule) => {
+                    let (content_type, content) =
+                        crate::decrypt_record(key_schedule.as_mut(), &hdr, hdr_buf, content)?;
+                    match content_type {
+                        TlsRecordType::ApplicationData => {
+                            events.push(Event::ApplicationData(content));
+                        }
+                        TlsRecordType::Alert => match alert::process_alert(&content) {
+                            Ok(()) => events.push(Event::UserCanceled),
+                            Err(e) if alert::is_close_notify(&e) => {
+                                self.read_closed = true;
+                                events.push(Event::PeerClosed);
+                            }
+                            Err(e) => return Err(e),
+                        },
+                        TlsRecordType::Handshake => {
+                            let handshake_buffer = &mut self.tls_record_reader.handshake_buffer;
+                            handshake_buffer.push(&content);
+                            while let Some(msg) = handshake_buffer.next_message()? {
+                                // Without post_handshake_auth in our ClientHello, a
+                                // CertificateRequest is an unexpected message.
+                                if certificate_request::is_certificate_request(&msg)
+                                    && self.config.post_handshake_auth
+                                {
+                                    if self.post_handshake.write_closed {
+                                        debug!("ignoring CertificateRequest after close_notify");
+                                        continue;
+                                    }
+                                    let auth = PostHandshakeAuth::new(&msg, key_schedule)?;
+                                    let request = auth.request().clone();
+                                    self.post_handshake_auth.push_back(auth);
+                                    events.push(Event::CertificateRequested(request));
+                                    continue;
+                                }
+                                if msg[0] == KEY_UPDATE {
+                                    // The following records use the new keys.
+                                    handshake_buffer.expect_key_change()?;
+                                }
+                                crate::process_post_handshake_message(
+                                    &mut self.outgoing,
+                                    key_schedule,
+                                    &msg,
+                                    &mut self.post_handshake,
+                                )?;
+                            }
+                        }
+                        _ => {
+                            return Err(alert::fatal(
+                                TlsAlertDescription::UnexpectedMessage,
+                                format!("unexpected {:?} record", content_type),
+                            ));
+                        }
+                    }
+                    false
+                }
+                State::Closed => return Ok(()),
+            };
+            if server_finished {
+                self.start_client_flight(events)?;
+            }
+        }
+    }
+
+    /// Sends our flight up to the client Certificate once the server's flight is verified.
+    /// Finishes the handshake straight away unless the server asked for a certificate.
+    fn start_client_flight(&mut self, events: &mut Vec<Event>) -> anyhow::Result<()> {
+        let State::Handshaking(handshake) = &mut self.state else {
+            anyhow::bail!("no handshake in progress");
+        };
+        let key_schedule = &mut handshake.key_schedule;
+        self.early_data_status = match &mut handshake.early_key_schedule {
+            None => EarlyDataStatus::NotSent,
+            Some(early) if handshake.encrypted_extensions.early_data_accepted => {
+                crate::send_encrypted_record(
+                    &mut self.outgoing,
+                    early,
+                    TlsRecordType::Handshake,
+                    &crate::END_OF_EARLY_DATA,
+                )?;
+                key_schedule.add_transcript(&crate::END_OF_EARLY_DATA);
+                EarlyDataStatus::Accepted
+            }
+            Some(_) => EarlyDataStatus::Rejected,
+        };
+        info!("early data: {:?}", self.early_data_status);
+
+        if handshake.hello_random.session_id.is_some() && handshake.early_key_schedule.is_none() {
+            crate::send_change_cipher_spec(&mut self.outgoing)?;
+        }
+        if let Some(request) = &handshake.certificate_request {
+            handshake.expect = Expect::ClientCertificate;
+            events.push(Event::CertificateRequested(request.clone()));
+            return Ok(());
+        }
+        self.finish_handshake(None, events)
+    }
+
+    /// Sends our Certificate and, with an identity, asks for the CertificateVerify signature
+    /// under the scheme negotiated for it. Without one the handshake finishes.
+    fn send_client_certificate(
+        &mut self,
+        identity: Option<&ClientIdentity>,
+        events: &mut Vec<Event>,
+    ) -> anyhow::Result<()> {
+        let State::Handshaking(handshake) = &mut self.state else {
+            anyhow::bail!("no handshake in progress");
+        };
+        let (Expect::ClientCertificate, Some(request)) =
+            (&handshake.expect, &handshake.certificate_request)
+        else {
+            anyhow::bail!("unexpected client certificate");
+        };
+        let key_schedule = &mut handshake.key_schedule;
+        let Some(identity) = identity else {
+            info!("no client certificate to send");
+            crate::send_client_cert(&mut self.outgoing, key_schedule, request, &[])?;
+            handshake.expect = Expect::ClientFlight;
+            return self.finish_handshake(None, events);
+        };
+        let scheme = cert_verify::select_client_signature_scheme(
+            &request.signature_schemes,
+            identity.signature_schemes(),
+        )?;
+        crate::send_client_cert(&mut self.outgoing, key_schedule, request, identity.chain())?;
+        handshake.expect = Expect::Signature { scheme };
+        events.push(Event::SignatureRequired(
+            scheme,
+            crate::cert_verify_signing_input(key_schedule),
+        ));
+        Ok(())
+    }
+
+    /// Sends CertificateVerify, if we sent the server a certificate, and Finished, then
+    /// switches to the application traffic keys.
+    fn finish_handshake(
+        &mut self,
+        signature: Option<&[u8]>,
+        events: &mut Vec<Event>,
+    ) -> anyhow::Result<()> {
+        let State::Handshaking(handshake) = &mut self.state else {
+            anyhow::bail!("no handshake in progress");
+        };
+        match (signature, &handshake.expect) {
+            (Some(signature), Expect::Signature { scheme }) => crate::send_cert_verify(
+                &mut self.outgoing,
+                &mut handshake.key_schedule,
+                *scheme,
+                signature,
+            )?,
+            (None, Expect::ClientFlight) => {}
+            _ => anyhow::bail!("unexpected CertificateVerify signature"),
+        }
+        crate::send_client_finished(&mut self.outgoing, &mut handshake.key_schedule)?;
+
+        let State::Handshaking(handshake) = std::mem::replace(&mut self.state, State::Closed)
+        else {
+            unreachable!();
+        };
+        let Handshake {
+            key_schedule,
+            encrypted_extensions,
+            ..
+        } = *handshake;
+        self.resumed = key_schedule.psk_accepted();
+        self.post_handshake.alpn_protocol = encrypted_extensions.alpn_protocol;
+        let key_schedule = key_schedule.into_application_key_schedule()?;
+        self.key_update_interval = self
+            .config
+            .key_update_interval
+            .unwrap_or(u64::MAX)
+            .min(key_schedule.cipher_suite.confidentiality_limit);
+        self.state = State::Connected(Box::new(key_schedule));
+        info!("handshake complete, resumed: {}", self.resumed);
+        events.push(Event::HandshakeComplete);
+        Ok(())
+    }
+
+    /// Adds our Certificate to the answer to the oldest CertificateRequest received after the
+    /// handshake. With an identity the CertificateVerify signature is asked for; without one
+    /// the answer is complete and sent.
+    fn answer_certificate_request(
+        &mut self,
+        identity: Option<&ClientIdentity>,
+        events: &mut Vec<Event>,
+    ) -> anyhow::Result<()> {
+        let Some(auth) = self.post_handshake_auth.front_mut() else {
+            anyhow::bail!("unexpected client certificate");
+        };
+        match auth.add_certificate(identity)? {
+            Some((scheme, signing_input)) => {
+                events.push(Event::SignatureRequired(scheme, signing_input));
+                Ok(())
+            }
+            None => self.send_post_handshake_auth(),
+        }
+    }
+
+    /// Sends the oldest answer to a post-handshake CertificateRequest, completed with
+    /// Finished, under the application traffic keys.
+    fn send_post_handshake_auth(&mut self) -> anyhow::Result<()> {
+        let State::Connected(key_schedule) = &mut self.state else {
+            anyhow::bail!("TLS connection not established");
+        };
+        let Some(auth) = self.post_handshake_auth.pop_front() else {
+            anyhow::bail!("no CertificateRequest to answer");
+        };
+        if self.post_handshake.write_closed {
+            debug!("dropping answer to CertificateRequest after close_notify");
+            return Ok(());
+        }
+        let messages = auth.finish(key_schedule)?;
+        crate::send_records(
+            &mut self.outgoing,
+            key_schedule.as_mut(),
+            TlsRecordType::Handshake,
+            &messages,
+        )
+    }
+
+    /// Updates the keys in either direction that has used its current key for
+    /// `key_update_interval` records. The server's keys can only be updated by asking it to.
+    fn update_keys_if_due(&mut self) -> anyhow::Result<()> {
+        let State::Connected(key_schedule) = &self.state else {
+            return Ok(());
+        };
+        if self.post_handshake.write_closed {
+            return Ok(());
+        }
+        let read_due = key_schedule.read_seq_num >= self.key_update_interval
+            && !self.post_handshake.key_update_requested;
+        if read_due || key_schedule.write_seq_num >= self.key_update_interval {
+            self.update_keys(read_due)?;
+        }
+        Ok(())
+    }
+}
+
+impl Handshake {
+    /// Whether our flight waits for the driver to choose a certificate or sign.
+    fn awaits_driver(&self) -> bool {
+        matches!(
+            self.expect,
+            Expect::ClientCertificate | Expect::Signature { .. }
+        )
+    }
+
+    /// Whether the server's flight is still coming in.
+    fn before_server_finished(&self) -> bool {
+        !matches!(self.expect, Expect::ClientFlight) && !self.awaits_driver()
+    }
+
+    /// ServerHello and HelloRetryRequest come before there are any keys.
+    fn expects_plaintext(&self) -> bool {
+        matches!(self.expect, Expect::ServerHello | Expect::ServerHelloAfterRetry)
+    }
+
+    /// Processes the complete messages in `handshake_buffer`. Returns true once the server
+    /// Finished has been verified.
+    fn process_messages(
+        &mut self,
+        handshake_buffer: &mut HandshakeBuffer,
+        config: &ClientConfig,
+        out: &mut Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        while let Some(raw) = handshake_buffer.next_message()? {
+            self.process_message(&raw, config, out)?;
+            match self.expect {
+                Expect::EncryptedExtensions => handshake_buffer.expect_key_change()?,
+                Expect::ClientFlight => {
+                    handshake_buffer.expect_key_change()?;
+                    return Ok(true);
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    fn process_message(
+        &mut self,
+        raw: &[u8],
+        config: &ClientConfig,
+        out: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        // Stands in until the next stage is known, keeping the alert for an error in plaintext
+        // while there are no handshake keys.
+        let placeholder = if self.expects_plaintext() {
+            Expect::ServerHello
+        } else {
+            Expect::ClientFlight
+        };
+        let key_schedule = &mut self.key_schedule;
+        self.expect = match std::mem::replace(&mut self.expect, placeholder) {
+            Expect::ServerHello if hello_retry::is_hello_retry_request(raw) => {
+                crate::process_hello_retry_request(
+                    out,
+                    key_schedule,
+                    config,
+                    &self.hello_random,
+                    self.ticket.as_ref(),
+                    raw,
+                    self.rng.as_ref(),
+                )?;
+                self.retried = true;
+                Expect::ServerHelloAfterRetry
+            }
+            Expect::ServerHelloAfterRetry if hello_retry::is_hello_retry_request(raw) => {
+                return Err(alert::fatal(
+                    TlsAlertDescription::UnexpectedMessage,
+                    "received a second HelloRetryRequest",
+                ));
+            }
+            Expect::ServerHello | Expect::ServerHelloAfterRetry => {
+                crate::process_server_hello(key_schedule, config, &self.hello_random, raw)?;
+                Expect::EncryptedExtensions
+            }
+            Expect::EncryptedExtensions => {
+                // A ClientHello sent after HelloRetryRequest cannot carry early data.
+                let early_data_ticket = self
+                    .ticket
+                    .as_ref()
+                    .filter(|_| self.early_key_schedule.is_some() && !self.retried);
+                self.encrypted_extensions = crate::parse_tls_extensions(
+                    raw,
+                    key_schedule,
+                    config,
+                    early_data_ticket.is_some(),
+                )?;
+                if let Some(ticket) =
+                    early_data_ticket.filter(|_| self.encrypted_extensions.early_data_accepted)
+                {
+                    crate::check_early_data_accepted(
+                        key_schedule,
+                        &self.encrypted_extensions,
+                        ticket,
+                    )?;
+                }
+                // A resumed handshake is authenticated by the PSK, so the server goes
+                // straight to Finished.
+                if key_schedule.psk_accepted() {
+                    Expect::Finished
+                } else {
+                    Expect::CertificateOrCertificateRequest
+                }
+            }
+            Expect::CertificateOrCertificateRequest
+                if certificate_request::is_certificate_request(raw) =>
+            {
+                let request = certificate_request::parse_certificate_request(raw)?;
+                // Only post-handshake requests need a context to tell answers apart.
+                if !request.context.is_empty() {
+                    return Err(alert::fatal(
+                        TlsAlertDescription::IllegalParameter,
+                        "CertificateRequest with a context during the handshake",
+                    ));
+                }
+                self.certificate_request = Some(request);
+                key_schedule.add_transcript(raw);
+                Expect::Certificate
+            }
+            Expect::CertificateOrCertificateRequest | Expect::Certificate => {
+                let leaf_cert = crate::process_server_cert(
+                    raw,
+                    key_schedule,
+                    &config.server_name,
+                    &config.trust_store,
+                )?;
+                Expect::CertificateVerify { leaf_cert }
+            }
+            Expect::CertificateVerify { leaf_cert } => {
+                crate::process_server_cert_verify(raw, key_schedule, &leaf_cert)?;
+                Expect::Finished
+            }
+            Expect::Finished => {
+                crate::process_finished(raw, key_schedule)?;
+                Expect::ClientFlight
+            }
+            Expect::ClientFlight | Expect::ClientCertificate | Expect::Signature { .. } => {
+                return Err(alert::fatal(
+                    TlsAlertDescription::UnexpectedMessage,
+                    format!("unexpected handshake message type {}", raw[0]),
+                ));
+            }
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher_suite::{TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256};
+    use crate::client_identity::IdentityStore;
+    use crate::resumption::SessionCache;
+    use crate::test_server::{
+        TestServer, certificate_request, client_identity, handshake, handshake_message,
+        key_share_extension, parse_client_hello, plaintext_record, received_alert, records,
+        server_hello_message,
+    };
+    use crate::trust_store::TrustStore;
+    use ring::rand::SystemRandom;
+    use ring::signature::KeyPair;
+    use ring::test::rand::FixedByteRandom;
+    use tls_parser::NamedGroup;
+
+    fn config() -> Arc<ClientConfig> {
+        let mut config = ClientConfig::new("localhost", TrustStore::empty()).unwrap();
+        // A key share for every group, so that each kind of key generation is covered.
+        config.key_shares = config.groups.len();
+        Arc::new(config)
+    }
+
+    fn client_hello(config: &Arc<ClientConfig>, seed: u8) -> Vec<u8> {
+        let rng = FixedByteRandom { byte: seed };
+        let mut connection = ClientConnection::new(config.clone(), &[], rng).unwrap();
+        connection.take_outgoing()
+    }
+
+    #[test]
+    fn client_hello_depends_only_on_rng() {
+        let config = config();
+        assert_eq!(client_hello(&config, 7), client_hello(&config, 7));
+        assert_ne!(client_hello(&config, 7), client_hello(&config, 8));
+    }
+
+    #[test]
+    fn server_flight_coalesced_into_one_record() {
+        handshake(usize::MAX, usize::MAX);
+    }
+
+    #[test]
+    fn server_flight_fragmented_across_records() {
+        handshake(7, usize::MAX);
+    }
+
+    #[test]
+    fn server_flight_received_byte_by_byte() {
+        handshake(100, 1);
+    }
+
+    #[test]
+    fn application_data_after_handshake() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        let data = server.seal(TlsRecordType::ApplicationData, b"hello", usize::MAX);
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(&events[..], [Event::ApplicationData(data)] if data == b"hello"));
+
+        client.send_application_data(b"hi").unwrap();
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::ApplicationData);
+        assert_eq!(content, b"hi");
+    }
+
+    #[test]
+    fn server_picks_secp256r1_or_secp384r1() {
+        for group in [NamedGroup::Secp256r1, NamedGroup::Secp384r1] {
+            let mut server = TestServer::new();
+            server.group = group;
+            let mut config = server.client_config();
+            config.key_shares = config.groups.len();
+            let (mut client, client_hello) = server.connect(config);
+            server.add_client_hello(&client_hello);
+            let mut data = server.server_hello(&client_hello);
+            let flight = server.flight(false);
+            data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+            let events = client.receive(&data).unwrap();
+            assert!(matches!(events[..], [Event::HandshakeComplete]));
+            server.finish(&client.take_outgoing());
+        }
+    }
+
+    #[test]
+    fn server_share_for_unsent_group_is_illegal_parameter() {
+        let server = TestServer::new();
+        let mut config = server.client_config();
+        // Only an X25519MLKEM768 share.
+        config.key_shares = 1;
+        let (mut client, client_hello) = server.connect(config);
+        let session_id = parse_client_hello(&client_hello).session_id;
+        let mut extensions = vec![0, 43, 0, 2, 3, 4];
+        extensions.extend_from_slice(&key_share_extension(NamedGroup::EcdhX25519, &[9; 32]));
+        let server_hello = server_hello_message(
+            &TLS13_AES_128_GCM_SHA256,
+            &[0x5A; 32],
+            &session_id,
+            &extensions,
+        );
+
+        let err = client
+            .receive(&plaintext_record(TlsRecordType::Handshake, &server_hello))
+            .unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::IllegalParameter)
+        );
+        // There are no handshake keys to protect the alert with yet.
+        let alert = alert::alert_payload(TlsAlertDescription::IllegalParameter);
+        assert_eq!(
+            client.take_outgoing(),
+            plaintext_record(TlsRecordType::Alert, &alert)
+        );
+    }
+
+    /// Runs a handshake in which the client only sends an X25519MLKEM768 share, so the
+    /// server has to ask for one for `group`.
+    fn handshake_after_hello_retry_request(group: NamedGroup) {
+        let mut server = TestServer::new();
+        server.group = group;
+        let mut config = server.client_config();
+        config.key_shares = 1;
+        let (mut client, client_hello1) = server.connect(config);
+        assert_eq!(parse_client_hello(&client_hello1).key_share(group), None);
+        server.add_client_hello(&client_hello1);
+
+        let hello_retry_request = server.hello_retry_request(&client_hello1);
+        assert!(client.receive(&hello_retry_request).unwrap().is_empty());
+        let client_hello2 = client.take_outgoing();
+        let retried = parse_client_hello(&client_hello2);
+        assert!(retried.key_share(group).is_some());
+        server.add_client_hello(&client_hello2);
+
+        let mut data = server.server_hello(&client_hello2);
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(events[..], [Event::HandshakeComplete]));
+        server.finish(&client.take_outgoing());
+    }
+
+    #[test]
+    fn hello_retry_request() {
+        handshake_after_hello_retry_request(NamedGroup::EcdhX25519);
+    }
+
+    #[test]
+    fn hello_retry_request_for_secp384r1() {
+        handshake_after_hello_retry_request(NamedGroup::Secp384r1);
+    }
+
+    #[test]
+    fn bad_server_finished_is_decrypt_error() {
+        let mut server = TestServer::new();
+        let (mut client, client_hello) = server.connect(server.client_config());
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        let flight = server.flight(true);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+
+        let err = client.receive(&data).unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::DecryptError)
+        );
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(
+            content,
+            alert::alert_payload(TlsAlertDescription::DecryptError)
+        );
+        assert!(client.receive(&[]).is_err());
+    }
+
+    #[test]
+    fn change_cipher_spec_during_handshake_is_dropped() {
+        let mut server = TestServer::new();
+        let (mut client, client_hello) = server.connect(server.client_config());
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        data.extend_from_slice(&plaintext_record(TlsRecordType::ChangeCipherSpec, &[1]));
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(events[..], [Event::HandshakeComplete]));
+        server.finish(&client.take_outgoing());
+    }
+
+    #[test]
+    fn change_cipher_spec_other_than_one_is_unexpected_message() {
+        let mut server = TestServer::new();
+        let (mut client, client_hello) = server.connect(server.client_config());
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        data.extend_from_slice(&plaintext_record(TlsRecordType::ChangeCipherSpec, &[2]));
+
+        let err = client.receive(&data).unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::UnexpectedMessage)
+        );
+    }
+
+    #[test]
+    fn change_cipher_spec_after_handshake_is_unexpected_message() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        let err = client
+            .receive(&plaintext_record(TlsRecordType::ChangeCipherSpec, &[1]))
+            .unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::UnexpectedMessage)
+        );
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(
+            content,
+            alert::alert_payload(TlsAlertDescription::UnexpectedMessage)
+        );
+    }
+
+    #[test]
+    fn certificate_request_context_during_handshake_is_illegal_parameter() {
+        let mut server = TestServer::new();
+        let (mut client, client_hello) = server.connect(server.client_config());
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        let mut messages = handshake_message(8, &[0, 0]);
+        // A one-byte certificate_request_context, then signature_algorithms with
+        // ecdsa_secp256r1_sha256.
+        messages.extend_from_slice(&handshake_message(
+            13,
+            &[1, 1, 0, 8, 0, 13, 0, 4, 0, 2, 4, 3],
+        ));
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &messages, usize::MAX));
+
+        let err = client.receive(&data).unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::IllegalParameter)
+        );
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(
+            content,
+            alert::alert_payload(TlsAlertDescription::IllegalParameter)
+        );
+    }
+
+    #[test]
+    fn fatal_alert_instead_of_server_hello() {
+        let server = TestServer::new();
+        let (mut client, _) = server.connect(server.client_config());
+        let alert = alert::alert_payload(TlsAlertDescription::HandshakeFailure);
+        let err = client
+            .receive(&plaintext_record(TlsRecordType::Alert, &alert))
+            .unwrap_err();
+        assert_eq!(
+            received_alert(&err),
+            Some(TlsAlertDescription::HandshakeFailure)
+        );
+        assert!(client.take_outgoing().is_empty());
+        assert!(client.receive(&[]).is_err());
+    }
+
+    #[test]
+    fn fatal_alert_during_handshake() {
+        let mut server = TestServer::new();
+        let (mut client, client_hello) = server.connect(server.client_config());
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        let alert = alert::alert_payload(TlsAlertDescription::InternalError);
+        data.extend_from_slice(&server.seal(TlsRecordType::Alert, &alert, usize::MAX));
+
+        let err = client.receive(&data).unwrap_err();
+        assert_eq!(
+            received_alert(&err),
+            Some(TlsAlertDescription::InternalError)
+        );
+        assert!(client.take_outgoing().is_empty());
+        assert!(client.receive(&[]).is_err());
+    }
+
+    #[test]
+    fn close_notify() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        let mut data = server.seal(TlsRecordType::ApplicationData, b"bye", usize::MAX);
+        data.extend_from_slice(&server.seal(
+            TlsRecordType::Alert,
+            &alert::CLOSE_NOTIFY,
+            usize::MAX,
+        ));
+        // Anything after close_notify is ignored.
+        data.extend_from_slice(&server.seal(TlsRecordType::ApplicationData, b"late", usize::MAX));
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(
+            &events[..],
+            [Event::ApplicationData(data), Event::PeerClosed] if data == b"bye"
+        ));
+
+        client.send_close_notify().unwrap();
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(content, alert::CLOSE_NOTIFY);
+        assert!(client.send_application_data(b"too late").is_err());
+    }
+
+    /// Asks for a certificate after the handshake and answers with our identity, checking
+    /// that application data received while the signature is pending is delivered.
+    fn authenticate_after_handshake(server: &mut TestServer, client: &mut ClientConnection) {
+        let (identity, key) = client_identity();
+        let request = certificate_request(&[7, 7], &[]);
+        let data = server.seal(TlsRecordType::Handshake, &request, usize::MAX);
+        let events = client.receive(&data).unwrap();
+        let [Event::CertificateRequested(certificate_request)] = &events[..] else {
+            panic!("expected a CertificateRequest, got {} events", events.len());
+        };
+        assert_eq!(certificate_request.context, [7, 7]);
+
+        let events = client.provide_certificate(Some(&identity)).unwrap();
+        let [Event::SignatureRequired(scheme, signing_input)] = &events[..] else {
+            panic!("expected a signature request, got {} events", events.len());
+        };
+        assert_eq!(*scheme, SignatureScheme::ecdsa_secp256r1_sha256);
+        let data = server.seal(TlsRecordType::ApplicationData, b"meanwhile", usize::MAX);
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(&events[..], [Event::ApplicationData(data)] if data == b"meanwhile"));
+        // The answer goes out once it is complete.
+        assert!(client.take_outgoing().is_empty());
+
+        let signature = key.sign(&SystemRandom::new(), signing_input).unwrap();
+        let events = client.provide_signature(signature.as_ref()).unwrap();
+        assert!(events.is_empty());
+        let certificate = server.check_certificate_answer(
+            &request,
+            &client.take_outgoing(),
+            Some(key.public_key().as_ref()),
+        );
+        // The context is echoed, followed by the chain.
+        assert_eq!(certificate[4..7], [2, 7, 7]);
+        assert!(certificate.ends_with(&[0, 0]));
+    }
+
+    #[test]
+    fn post_handshake_auth() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        authenticate_after_handshake(&mut server, &mut client);
+    }
+
+    #[test]
+    fn post_handshake_auth_after_key_update() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        client.update_keys(false).unwrap();
+        let (content_type, _) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Handshake);
+        authenticate_after_handshake(&mut server, &mut client);
+    }
+
+    #[test]
+    fn post_handshake_auth_without_matching_identity() {
+        let (mut server, mut client) = handshake(usize::MAX, usize::MAX);
+        let mut identities = IdentityStore::new();
+        identities.add(client_identity().0, ());
+        identities.set_default(None).unwrap();
+        // An empty distinguished name, which names no CA of ours.
+        let request = certificate_request(&[9], &[&[0x30, 0]]);
+        let data = server.seal(TlsRecordType::Handshake, &request, usize::MAX);
+        let events = client.receive(&data).unwrap();
+        let [Event::CertificateRequested(certificate_request)] = &events[..] else {
+            panic!("expected a CertificateRequest, got {} events", events.len());
+        };
+        assert_eq!(identities.select(certificate_request), None);
+
+        assert!(client.provide_certificate(None).unwrap().is_empty());
+        let certificate = server.check_certificate_answer(&request, &client.take_outgoing(), None);
+        assert_eq!(certificate, handshake_message(11, &[1, 9, 0, 0, 0]));
+    }
+
+    /// Runs a full handshake in which the server issues a ticket allowing 0-RTT data, and
+    /// returns the session cache holding it.
+    fn session_cache_with_ticket(server: &mut TestServer) -> SessionCache {
+        let config = server.client_config();
+        let session_cache = config.session_cache.clone();
+        let (mut client, client_hello) = server.connect(config);
+        server.add_client_hello(&client_hello);
+        let mut data = server.server_hello(&client_hello);
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(events[..], [Event::HandshakeComplete]));
+        server.finish(&client.take_outgoing());
+
+        let ticket = server.new_session_ticket(1024);
+        let data = server.seal(TlsRecordType::Handshake, &ticket, usize::MAX);
+        assert!(client.receive(&data).unwrap().is_empty());
+        session_cache
+    }
+
+    /// Resumes with 0-RTT data, which the server accepts or rejects as `accept_early_data`
+    /// says, and returns the early data status the client ends up with.
+    fn resume_with_early_data(accept_early_data: bool) -> EarlyDataStatus {
+        let mut server = TestServer::new();
+        let mut config = server.client_config();
+        config.session_cache = session_cache_with_ticket(&mut server);
+        let (mut client, client_flight) = server.connect_with_early_data(config, b"early");
+        let records = records(&client_flight);
+        assert_eq!(records.len(), 2);
+        server.resume(records[0]);
+        // Protected under client_early_traffic_secret.
+        let (content_type, early_data) = server.open_early_data(records[1]);
+        assert_eq!(content_type, TlsRecordType::ApplicationData);
+        assert_eq!(early_data, b"early");
+
+        server.accept_early_data = accept_early_data;
+        let mut data = server.server_hello(records[0]);
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(events[..], [Event::HandshakeComplete]));
+        assert!(client.resumed());
+        // Checks for EndOfEarlyData under the early traffic keys exactly when the early data
+        // was accepted, and that the client Finished covers it.
+        server.finish(&client.take_outgoing());
+        client.early_data_status()
+    }
+
+    #[test]
+    fn early_data_accepted() {
+        assert_eq!(resume_with_early_data(true), EarlyDataStatus::Accepted);
+    }
+
+    #[test]
+    fn early_data_rejected() {
+        assert_eq!(resume_with_early_data(false), EarlyDataStatus::Rejected);
+    }
+
+    #[test]
+    fn early_data_rejected_by_hello_retry_request() {
+        let mut server = TestServer::new();
+        let mut config = server.client_config();
+        config.session_cache = session_cache_with_ticket(&mut server);
+        // Only an X25519MLKEM768 share, so the server asks for an X25519 one.
+        config.key_shares = 1;
+        let (mut client, client_flight) = server.connect_with_early_data(config, b"early");
+        let records1 = records(&client_flight);
+        assert_eq!(records1.len(), 2);
+        server.resume(records1[0]);
+
+        let hello_retry_request = server.hello_retry_request(records1[0]);
+        assert!(client.receive(&hello_retry_request).unwrap().is_empty());
+        let outgoing = client.take_outgoing();
+        // The second ClientHello still offers the ticket, but no early data follows it.
+        let records2 = records(&outgoing);
+        assert_eq!(records2.len(), 1);
+        let retried = parse_client_hello(records2[0]);
+        assert!(retried.pre_shared_key);
+        assert!(!retried.early_data);
+        server.add_client_hello(records2[0]);
+
+        let mut data = server.server_hello(records2[0]);
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+        let events = client.receive(&data).unwrap();
+        assert!(matches!(events[..], [Event::HandshakeComplete]));
+        assert!(client.resumed());
+        assert_eq!(client.early_data_status(), EarlyDataStatus::Rejected);
+        // Only Finished, without EndOfEarlyData.
+        server.finish(&client.take_outgoing());
+    }
+
+    /// Resumes with 0-RTT data, which `server` accepts for a session that is not the
+    /// ticket's, and checks that the client fails with illegal_parameter.
+    fn early_data_accepted_for_other_session(mut server: TestServer, config: ClientConfig) {
+        let (mut client, client_flight) = server.connect_with_early_data(config, b"early");
+        let records = records(&client_flight);
+        assert_eq!(records.len(), 2);
+        server.resume(records[0]);
+        server.accept_early_data = true;
+        let mut data = server.server_hello(records[0]);
+        let flight = server.flight(false);
+        data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, usize::MAX));
+
+        let err = client.receive(&data).unwrap_err();
+        assert_eq!(
+            alert::alert_for(&err),
+            Some(TlsAlertDescription::IllegalParameter)
+        );
+        let (content_type, content) = server.open(&client.take_outgoing());
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(
+            content,
+            alert::alert_payload(TlsAlertDescription::IllegalParameter)
+        );
+    }
+
+    #[test]
+    fn early_data_accepted_with_other_cipher_suite_is_illegal_parameter() {
+        let mut server = TestServer::new();
+        let mut config = server.client_config();
+        config.session_cache = session_cache_with_ticket(&mut server);
+        // The ticket is for TLS_AES_128_GCM_SHA256; its PSK works with any SHA-256 suite.
+        server.cipher_suite = &TLS13_CHACHA20_POLY1305_SHA256;
+        early_data_accepted_for_other_session(server, config);
+    }
+
+    #[test]
+    fn early_data_accepted_with_other_alpn_protocol_is_illegal_parameter() {
+        let mut server = TestServer::new();
+        let mut config = server.client_config();
+        config.session_cache = session_cache_with_ticket(&mut server);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        // The ticket comes from a connection without ALPN.
+        server.alpn_protocol = Some(b"h2".to_vec());
+        early_data_accepted_for_other_session(server, config);
+    }
+}
@@ -0,0 +1,421 @@
+use crate::certificate_request::{CertificateRequest, OidFilter};
+use der::oid::{AssociatedOid, ObjectIdentifier};
+use der::{Decode, Encode};
+use log::{debug, info};
+use std::path::Path;
+use tls_parser::{SignatureScheme, TlsExtensionType};
+use x509_cert::certificate::Certificate;
+use x509_cert::ext::pkix::{ExtendedKeyUsage, KeyUsage};
+
+const CERTIFICATE: u8 = 11;
+
+/// Signs our CertificateVerify with the private key of a [`ClientIdentity`].
+pub(crate) trait ClientSigner {
+    fn sign(&self, scheme: SignatureScheme, msg: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// A [`ClientSigner`] for [`crate::async_tls_stream::AsyncTlsStream`], which awaits the
+/// signature instead of blocking the executor on it. The future is `Send` so that a
+/// signature for post-handshake authentication can be awaited while reading goes on.
+pub(crate) trait AsyncClientSigner {
+    fn sign(
+        &self,
+        scheme: SignatureScheme,
+        msg: Vec<u8>,
+    ) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+}
+
+/// One CertificateEntry of our Certificate message.
+#[derive(Clone, Debug)]
+pub(crate) struct CertificateEntry {
+    pub(crate) cert_data: Vec<u8>,
+    /// Extensions sent with this certificate, such as an OCSP response in status_request.
+    /// Only those whose type the CertificateRequest contained are sent.
+    pub(crate) extensions: Vec<(TlsExtensionType, Vec<u8>)>,
+}
+
+impl CertificateEntry {
+    pub(crate) fn new(cert_data: Vec<u8>) -> Self {
+        Self {
+            cert_data,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+/// A certificate chain we can authenticate with and the schemes its key signs with.
+pub(crate) struct ClientIdentity {
+    chain: Vec<CertificateEntry>,
+    signature_schemes: Vec<SignatureScheme>,
+    leaf: Certificate,
+    /// DER-encoded names of the CAs in the chain: every issuer and every intermediate's
+    /// subject. The leaf's own subject is not among them.
+    names: Vec<Vec<u8>>,
+}
+
+impl ClientIdentity {
+    /// `chain` is the leaf followed by its intermediates; `signature_schemes` are those the
+    /// leaf's key can sign with. The server's preference decides among them.
+    pub(crate) fn new(
+        chain: Vec<CertificateEntry>,
+        signature_schemes: Vec<SignatureScheme>,
+    ) -> anyhow::Result<Self> {
+        let certs = chain
+            .iter()
+            .map(|entry| Certificate::from_der(&entry.cert_data))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("invalid client certificate: {:?}", e))?;
+        let Some(leaf) = certs.first().cloned() else {
+            anyhow::bail!("empty client certificate chain");
+        };
+        let mut names = Vec::new();
+        for cert in &certs {
+            names.push(cert.tbs_certificate.issuer.to_der()?);
+        }
+        for cert in &certs[1..] {
+            names.push(cert.tbs_certificate.subject.to_der()?);
+        }
+        Ok(Self {
+            chain,
+            signature_schemes,
+            leaf,
+            names,
+        })
+    }
+
+    pub(crate) fn chain(&self) -> &[CertificateEntry] {
+        &self.chain
+    }
+
+    pub(crate) fn signature_schemes(&self) -> &[SignatureScheme] {
+        &self.signature_schemes
+    }
+
+    /// Whether our key signs with a scheme `request` accepts.
+    fn can_sign(&self, request: &CertificateRequest) -> bool {
+        self.signature_schemes
+            .iter()
+            .any(|scheme| request.signature_schemes.contains(scheme))
+    }
+
+    /// Whether our key can sign for `request`, the chain leads to one of the CAs it lists
+    /// and the leaf satisfies its oid_filters.
+    fn matches(&self, request: &CertificateRequest) -> anyhow::Result<bool> {
+        if !self.can_sign(request) {
+            return Ok(false);
+        }
+        let issued_by_listed_ca = request.certificate_authorities.is_empty()
+            || request
+                .certificate_authorities
+                .iter()
+                .any(|name| self.names.contains(name));
+        if !issued_by_listed_ca {
+            return Ok(false);
+        }
+        for filter in &request.oid_filters {
+            if !self.satisfies(filter)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Checks the leaf against one oid_filters entry. Only KeyUsage and ExtendedKeyUsage are
+    /// understood; RFC 8446 has the client ignore filters on other extensions.
+    fn satisfies(&self, filter: &OidFilter) -> anyhow::Result<bool> {
+        let Ok(oid) = ObjectIdentifier::from_bytes(&filter.oid) else {
+            debug!(
+                "ignoring oid_filters entry with invalid OID {:02X?}",
+                filter.oid
+            );
+            return Ok(true);
+        };
+        let tbs = &self.leaf.tbs_certificate;
+        if oid == KeyUsage::OID {
+            let Some((_, key_usage)) = tbs.get::<KeyUsage>()? else {
+                return Ok(false);
+            };
+            if filter.values.is_empty() {
+                return Ok(true);
+            }
+            let wanted = KeyUsage::from_der(&filter.values)?;
+            Ok(key_usage.0.contains(wanted.0))
+        } else if oid == ExtendedKeyUsage::OID {
+            let Some((_, eku)) = tbs.get::<ExtendedKeyUsage>()? else {
+                return Ok(false);
+            };
+            if filter.values.is_empty() {
+                return Ok(true);
+            }
+            let wanted = ExtendedKeyUsage::from_der(&filter.values)?;
+            Ok(wanted.0.iter().all(|purpose| eku.0.contains(purpose)))
+        } else {
+            debug!("ignoring oid_filters entry for unknown extension {}", oid);
+            Ok(true)
+        }
+    }
+}
+
+/// The identities a client can authenticate with, each paired with the signer for its key.
+pub(crate) struct IdentityStore<S> {
+    identities: Vec<(ClientIdentity, S)>,
+    default: Option<usize>,
+}
+
+impl<S> IdentityStore<S> {
+    pub(crate) fn new() -> Self {
+        Self {
+            identities: Vec::new(),
+            default: None,
+        }
+    }
+
+    /// Adds an identity and returns its index. The first one added becomes the default.
+    pub(crate) fn add(&mut self, identity: ClientIdentity, signer: S) -> usize {
+        self.identities.push((identity, signer));
+        let index = self.identities.len() - 1;
+        self.default.get_or_insert(index);
+        index
+    }
+
+    /// Sets the identity used when none matches a CertificateRequest. With `None` the
+    /// client sends no certificate then.
+    pub(crate) fn set_default(&mut self, index: Option<usize>) -> anyhow::Result<()> {
+        if index.is_some_and(|index| index >= self.identities.len()) {
+            anyhow::bail!("no client identity {:?}", index);
+        }
+        self.default = index;
+        Ok(())
+    }
+
+    pub(crate) fn identity(&self, index: usize) -> &ClientIdentity {
+        &self.identities[index].0
+    }
+
+    pub(crate) fn signer(&self, index: usize) -> &S {
+        &self.identities[index].1
+    }
+
+    /// Chooses the identity to answer `request` with: the first, in the order they were
+    /// added, that [`ClientIdentity::matches`] it, or else the default. Requests without
+    /// certificate_authorities and oid_filters get the default. Either way the default is
+    /// only used if its key signs with a scheme the server accepts.
+    pub(crate) fn select(&self, request: &CertificateRequest) -> Option<usize> {
+        let default = self
+            .default
+            .filter(|&index| self.identity(index).can_sign(request));
+        if request.certificate_authorities.is_empty() && request.oid_filters.is_empty() {
+            return default;
+        }
+        let matched = self.identities.iter().position(|(identity, _)| {
+            identity.matches(request).unwrap_or_else(|e| {
+                info!("skipping client identity that cannot be matched: {:?}", e);
+                false
+            })
+        });
+        let selected = matched.or(default);
+        info!("selected client identity {:?}", selected);
+        selected
+    }
+}
+
+/// Loads the certificates of a PEM/DER file, in file order, as chain entries without
+/// extensions. Used for the intermediates between our leaf and the server's trust anchor.
+pub(crate) fn load_chain(path: &Path) -> anyhow::Result<Vec<CertificateEntry>> {
+    let data = std::fs::read(path)?;
+    let certs = if data.starts_with(b"-----BEGIN") {
+        Certificate::load_pem_chain(&data)
+            .map_err(|e| anyhow::anyhow!("load_pem_chain failed: {:?}", e))?
+    } else {
+        vec![
+            Certificate::from_der(&data)
+                .map_err(|e| anyhow::anyhow!("Certificate::from_der failed: {:?}", e))?,
+        ]
+    };
+    info!(
+        "loaded {} chain certificates from {}",
+        certs.len(),
+        path.display()
+    );
+    certs
+        .iter()
+        .map(|cert| Ok(CertificateEntry::new(cert.to_der()?)))
+        .collect()
+}
+
+fn push_u24(buf: &mut Vec<u8>, len: usize) -> anyhow::Result<()> {
+    if len >= 1 << 24 {
+        anyhow::bail!("{} bytes do not fit a u24 length", len);
+    }
+    buf.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+    Ok(())
+}
+
+fn push_u16(buf: &mut Vec<u8>, len: usize) -> anyhow::Result<()> {
+    let len = u16::try_from(len).map_err(|_| anyhow::anyhow!("{} bytes exceed u16", len))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Encodes a Certificate message (RFC 8446, section 4.4.2) answering `request`. An empty
+/// `chain` tells the server we have no certificate for it.
+pub(crate) fn encode_certificate(
+    request: &CertificateRequest,
+    chain: &[CertificateEntry],
+) -> anyhow::Result<Vec<u8>> {
+    let mut certificate_list = Vec::new();
+    for entry in chain {
+        push_u24(&mut certificate_list, entry.cert_data.len())?;
+        certificate_list.extend_from_slice(&entry.cert_data);
+        let mut extensions = Vec::new();
+        for (ext_type, data) in &entry.extensions {
+            if !request.extension_types.contains(ext_type) {
+                debug!(
+                    "leaving out certificate extension {} the server did not ask for",
+                    ext_type.0
+                );
+                continue;
+            }
+            extensions.extend_from_slice(&ext_type.0.to_be_bytes());
+            push_u16(&mut extensions, data.len())?;
+            extensions.extend_from_slice(data);
+        }
+        push_u16(&mut certificate_list, extensions.len())?;
+        certificate_list.extend_from_slice(&extensions);
+    }
+    let context_len = u8::try_from(request.context.len())
+        .map_err(|_| anyhow::anyhow!("certificate_request_context too long"))?;
+    let mut body = vec![context_len];
+    body.extend_from_slice(&request.context);
+    push_u24(&mut body, certificate_list.len())?;
+    body.extend_from_slice(&certificate_list);
+
+    let mut msg = vec![CERTIFICATE];
+    push_u24(&mut msg, body.len())?;
+    msg.extend_from_slice(&body);
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    fn certificate_request(
+        context: &[u8],
+        extension_types: Vec<TlsExtensionType>,
+    ) -> CertificateRequest {
+        CertificateRequest {
+            context: context.to_vec(),
+            signature_schemes: vec![SignatureScheme::ecdsa_secp256r1_sha256],
+            certificate_authorities: Vec::new(),
+            oid_filters: Vec::new(),
+            extension_types,
+        }
+    }
+
+    fn params(common_name: &str, is_ca: bool) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        params
+    }
+
+    /// A leaf and the intermediate that issued it under a root, along with the DER-encoded
+    /// names of root, intermediate and leaf.
+    fn chain() -> (Vec<CertificateEntry>, Vec<Vec<u8>>) {
+        let root_key = KeyPair::generate().unwrap();
+        let root = params("Root", true).self_signed(&root_key).unwrap();
+        let intermediate_key = KeyPair::generate().unwrap();
+        let intermediate = params("Intermediate", true)
+            .signed_by(&intermediate_key, &root, &root_key)
+            .unwrap();
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = params("Leaf", false)
+            .signed_by(&leaf_key, &intermediate, &intermediate_key)
+            .unwrap();
+        let names = [&root, &intermediate, &leaf]
+            .iter()
+            .map(|cert| {
+                let cert = Certificate::from_der(cert.der()).unwrap();
+                cert.tbs_certificate.subject.to_der().unwrap()
+            })
+            .collect();
+        let chain = [&leaf, &intermediate]
+            .iter()
+            .map(|cert| CertificateEntry::new(cert.der().to_vec()))
+            .collect();
+        (chain, names)
+    }
+
+    #[test]
+    fn selects_by_issuing_ca_not_by_leaf_subject() {
+        let (chain, names) = chain();
+        let mut store = IdentityStore::new();
+        let identity =
+            ClientIdentity::new(chain, vec![SignatureScheme::ecdsa_secp256r1_sha256]).unwrap();
+        store.add(identity, ());
+        store.set_default(None).unwrap();
+        let mut request = certificate_request(&[], Vec::new());
+        for (name, selected) in names.into_iter().zip([Some(0), Some(0), None]) {
+            request.certificate_authorities = vec![name];
+            assert_eq!(store.select(&request), selected);
+        }
+    }
+
+    #[test]
+    fn selects_an_identity_that_can_sign() {
+        let (chain, names) = chain();
+        let mut store = IdentityStore::new();
+        for scheme in [
+            SignatureScheme::rsa_pss_rsae_sha256,
+            SignatureScheme::ecdsa_secp256r1_sha256,
+        ] {
+            let identity = ClientIdentity::new(chain.clone(), vec![scheme]).unwrap();
+            store.add(identity, ());
+        }
+        let mut request = certificate_request(&[], Vec::new());
+        // The default is not used when its key cannot sign for the server.
+        assert_eq!(store.select(&request), None);
+        request.certificate_authorities = vec![names[0].clone()];
+        assert_eq!(store.select(&request), Some(1));
+    }
+
+    #[test]
+    fn certificate_only_carries_requested_extensions() {
+        let mut entry = CertificateEntry::new(vec![0xAA, 0xBB]);
+        entry.extensions = vec![
+            (TlsExtensionType::StatusRequest, vec![1]),
+            (TlsExtensionType::SignedCertificateTimestamp, vec![2]),
+        ];
+        let request = certificate_request(
+            &[7],
+            vec![
+                TlsExtensionType::SignatureAlgorithms,
+                TlsExtensionType::StatusRequest,
+            ],
+        );
+        let msg = encode_certificate(&request, &[entry.clone()]).unwrap();
+        let expected = [
+            &[CERTIFICATE, 0, 0, 17][..],
+            // certificate_request_context
+            &[1, 7],
+            // certificate_list with the one entry and only its status_request extension
+            &[0, 0, 12],
+            &[0, 0, 2, 0xAA, 0xBB],
+            &[0, 5, 0, 5, 0, 1, 1],
+        ]
+        .concat();
+        assert_eq!(msg, expected);
+
+        let msg = encode_certificate(&certificate_request(&[], Vec::new()), &[entry]).unwrap();
+        assert_eq!(
+            msg,
+            [CERTIFICATE, 0, 0, 11, 0, 0, 0, 7, 0, 0, 2, 0xAA, 0xBB, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use tls_parser::TlsExtensionType;
+use tls_parser::nom;
+use tls_parser::nom::bytes::complete::take;
+use tls_parser::nom::multi::length_data;
+use tls_parser::nom::number::complete::{be_u8, be_u16, be_u24};
+
+/// An extension as it appears on the wire, for extensions tls-parser does not decode for us.
+#[derive(Debug)]
+pub(crate) struct RawExtension<'a> {
+    pub(crate) ext_type: TlsExtensionType,
+    pub(crate) data: &'a [u8],
+}
+
+/// Splits a handshake message into its type and body.
+pub(crate) fn split_handshake(msg: &[u8]) -> anyhow::Result<(u8, &[u8])> {
+    let (i, msg_type) = be_u8(msg)
+        .map_err(|e: nom::Err<nom::error::Error<_>>| anyhow::anyhow!("be_u8 failed: {:?}", e))?;
+    let (i, len) = be_u24(i)
+        .map_err(|e: nom::Err<nom::error::Error<_>>| anyhow::anyhow!("be_u24 failed: {:?}", e))?;
+    let (i, body) = take(len as usize)(i)
+        .map_err(|e: nom::Err<nom::error::Error<_>>| anyhow::anyhow!("take failed: {:?}", e))?;
+    if !i.is_empty() {
+        anyhow::bail!("trailing data after handshake message type {}", msg_type);
+    }
+    Ok((msg_type, body))
+}
+
+/// Parses a u16 length prefixed extension block.
+pub(crate) fn parse_extensions(i: &[u8]) -> anyhow::Result<Vec<RawExtension<'_>>> {
+    let (rest, mut i) = length_data(be_u16)(i).map_err(|e: nom::Err<nom::error::Error<_>>| {
+        anyhow::anyhow!("extensions length_data failed: {:?}", e)
+    })?;
+    if !rest.is_empty() {
+        anyhow::bail!("trailing data after extensions");
+    }
+    let mut extensions = Vec::new();
+    while !i.is_empty() {
+        let (rest, ext_type) = be_u16(i).map_err(|e: nom::Err<nom::error::Error<_>>| {
+            anyhow::anyhow!("extension type failed: {:?}", e)
+        })?;
+        let (rest, data) =
+            length_data(be_u16)(rest).map_err(|e: nom::Err<nom::error::Error<_>>| {
+                anyhow::anyhow!("extension data failed: {:?}", e)
+            })?;
+        if extensions
+            .iter()
+            .any(|ext: &RawExtension| ext.ext_type.0 == ext_type)
+        {
+            anyhow::bail!("duplicate extension {}", ext_type);
+        }
+        extensions.push(RawExtension {
+            ext_type: TlsExtensionType(ext_type),
+            data,
+        });
+        i = rest;
+    }
+    Ok(extensions)
+}
+
+/// Parses the fields of a ServerHello (or HelloRetryRequest) body that precede its
+/// extensions and returns the cipher suite.
+pub(crate) fn parse_server_hello_fixed_fields(i: &[u8]) -> nom::IResult<&[u8], u16> {
+    let (i, _legacy_version) = be_u16(i)?;
+    let (i, _random) = take(32usize)(i)?;
+    let (i, _legacy_session_id) = length_data(be_u8)(i)?;
+    let (i, cipher_suite) = be_u16(i)?;
+    let (i, _legacy_compression_method) = be_u8(i)?;
+    Ok((i, cipher_suite))
+}
+
+/// Returns the raw extensions of a ServerHello handshake message.
+pub(crate) fn server_hello_extensions(msg: &[u8]) -> anyhow::Result<Vec<RawExtension<'_>>> {
+    let (_, body) = split_handshake(msg)?;
+    let (i, _) = parse_server_hello_fixed_fields(body)
+        .map_err(|e| anyhow::anyhow!("ServerHello fixed fields failed: {:?}", e))?;
+    parse_extensions(i)
+}
+
+pub(crate) fn find_extension<'a>(
+    extensions: &[RawExtension<'a>],
+    ext_type: TlsExtensionType,
+) -> Option<&'a [u8]> {
+    extensions
+        .iter()
+        .find(|ext| ext.ext_type == ext_type)
+        .map(|ext| ext.data)
+}
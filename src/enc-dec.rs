@@ -1,12 +1,11 @@
-use crate::key_schedule::{ApplicationKeySchedule, HandshakeKeySchedule};
+use crate::cipher_suite::CipherSuite;
+use crate::key_schedule::{ApplicationKeySchedule, EarlyKeySchedule, HandshakeKeySchedule};
 use log::{debug, info};
 use ring::aead::UnboundKey;
 
-impl TlsEncryptDecrypt for ApplicationKeySchedule {
-    fn get_read_seq_num_and_incr(&mut self) -> u64 {
-        let seq_num = self.read_seq_num;
-        self.read_seq_num += 1;
-        seq_num
+impl TlsEncrypt for ApplicationKeySchedule {
+    fn cipher_suite(&self) -> &'static CipherSuite {
+        self.cipher_suite
     }
     fn get_write_seq_num_and_incr(&mut self) -> u64 {
         let seq_num = self.write_seq_num;
@@ -19,6 +18,14 @@ impl TlsEncryptDecrypt for ApplicationKeySchedule {
     fn encryption_iv(&self) -> &[u8] {
         self.client_write_iv.as_ref()
     }
+}
+
+impl TlsEncryptDecrypt for ApplicationKeySchedule {
+    fn get_read_seq_num_and_incr(&mut self) -> u64 {
+        let seq_num = self.read_seq_num;
+        self.read_seq_num += 1;
+        seq_num
+    }
     fn decryption_key(&self) -> &[u8] {
         self.server_write_key.as_ref()
     }
@@ -30,6 +37,10 @@ impl TlsEncryptDecrypt for ApplicationKeySchedule {
         self.client_application_traffic_secret.as_ref()
     }
 
+    fn server_traffic_secret(&self) -> &[u8] {
+        self.server_application_traffic_secret.as_ref()
+    }
+
     fn transcript_hash_context_mut(&mut self) -> &mut ring::digest::Context {
         &mut self.transcript_hash_context
     }
@@ -39,11 +50,9 @@ impl TlsEncryptDecrypt for ApplicationKeySchedule {
     }
 }
 
-impl TlsEncryptDecrypt for HandshakeKeySchedule {
-    fn get_read_seq_num_and_incr(&mut self) -> u64 {
-        let seq_num = self.read_seq_num;
-        self.read_seq_num += 1;
-        seq_num
+impl TlsEncrypt for HandshakeKeySchedule {
+    fn cipher_suite(&self) -> &'static CipherSuite {
+        self.cipher_suite
     }
     fn get_write_seq_num_and_incr(&mut self) -> u64 {
         let seq_num = self.write_seq_num;
@@ -56,6 +65,14 @@ impl TlsEncryptDecrypt for HandshakeKeySchedule {
     fn encryption_iv(&self) -> &[u8] {
         self.client_write_iv.as_ref()
     }
+}
+
+impl TlsEncryptDecrypt for HandshakeKeySchedule {
+    fn get_read_seq_num_and_incr(&mut self) -> u64 {
+        let seq_num = self.read_seq_num;
+        self.read_seq_num += 1;
+        seq_num
+    }
     fn decryption_key(&self) -> &[u8] {
         self.server_write_key.as_ref()
     }
@@ -65,6 +82,9 @@ impl TlsEncryptDecrypt for HandshakeKeySchedule {
     fn client_traffic_secret(&self) -> &[u8] {
         self.client_handshake_traffic_secret.as_ref()
     }
+    fn server_traffic_secret(&self) -> &[u8] {
+        self.server_handshake_traffic_secret.as_ref()
+    }
     fn transcript_hash_context_mut(&mut self) -> &mut ring::digest::Context {
         &mut self.transcript_hash_context
     }
@@ -73,6 +93,24 @@ impl TlsEncryptDecrypt for HandshakeKeySchedule {
     }
 }
 
+/// The server never writes with early keys, so they only encrypt.
+impl TlsEncrypt for EarlyKeySchedule {
+    fn cipher_suite(&self) -> &'static CipherSuite {
+        self.cipher_suite
+    }
+    fn get_write_seq_num_and_incr(&mut self) -> u64 {
+        let seq_num = self.write_seq_num;
+        self.write_seq_num += 1;
+        seq_num
+    }
+    fn encryption_key(&self) -> &[u8] {
+        self.client_write_key.as_ref()
+    }
+    fn encryption_iv(&self) -> &[u8] {
+        self.client_write_iv.as_ref()
+    }
+}
+
 fn derive_nonce(iv: &[u8], seq_num: u64) -> Vec<u8> {
     let mut nonce = vec![0u8; 12];
     nonce[4..].copy_from_slice(&seq_num.to_be_bytes());
@@ -82,12 +120,74 @@ fn derive_nonce(iv: &[u8], seq_num: u64) -> Vec<u8> {
     nonce
 }
 
-pub trait TlsEncryptDecrypt {
+/// verify_data of a Finished message: an HMAC over `transcript_hash` keyed with the finished
+/// key derived from `traffic_secret` (RFC 8446, section 4.4.4).
+pub(crate) fn verify_data(
+    cipher_suite: &CipherSuite,
+    traffic_secret: &[u8],
+    transcript_hash: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let finished_key = crate::key_schedule::HKDF::new(cipher_suite, traffic_secret).expand_label(
+        &crate::key_schedule::HkdfLabel::new(cipher_suite.hash_len() as u16, "finished", b""),
+    )?;
+    // The verify_data field is an HMAC over the transcript hash using finished_key.
+    // The HMAC is computed as follows:
+    // HMAC(finished_key, transcript_hash)
+    let key = ring::hmac::Key::new(*cipher_suite.hmac, &finished_key);
+    let verify_data = ring::hmac::sign(&key, transcript_hash);
+    debug!(
+        "verify_data: {:02X?} key: {:02X?} digest: {:02X?}",
+        verify_data.as_ref(),
+        finished_key,
+        transcript_hash
+    );
+    Ok(verify_data.as_ref().to_vec())
+}
+
+/// The write side of a set of traffic keys.
+pub trait TlsEncrypt {
+    fn cipher_suite(&self) -> &'static CipherSuite;
+    fn get_write_seq_num_and_incr(&mut self) -> u64;
+    fn encryption_key(&self) -> &[u8];
+    fn encryption_iv(&self) -> &[u8];
+
+    fn encrypt_tls_plaintext<'a>(
+        &mut self,
+        hdr_buf: [u8; 5],
+        tls_plaintext: &'a mut [u8],
+    ) -> anyhow::Result<(&'a [u8], ring::aead::Tag)> {
+        let seq_num = self.get_write_seq_num_and_incr();
+        let nonce = derive_nonce(self.encryption_iv(), seq_num);
+        debug!(
+            "[encrypt_tls_plaintext] nonce: {:02X?}, key:{:02X?}, seq_num: {} tls_plaintext({}): {:02X?}",
+            nonce,
+            self.encryption_key(),
+            seq_num,
+            tls_plaintext.len(),
+            tls_plaintext
+        );
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(&nonce)
+            .map_err(|e| anyhow::anyhow!("try_assume_unique_for_key failed: {:?}", e))?;
+        if self.encryption_key().is_empty() {
+            return Err(anyhow::anyhow!("server_write_key is empty"));
+        }
+        let server_write_key = UnboundKey::new(self.cipher_suite().aead, self.encryption_key())
+            .map_err(|e| anyhow::anyhow!("UnboundKey failed: {:?}", e))?;
+        let aad = ring::aead::Aad::from(&hdr_buf);
+        let tag = ring::aead::LessSafeKey::new(server_write_key)
+            .seal_in_place_separate_tag(nonce, aad, tls_plaintext)
+            .map_err(|e| anyhow::anyhow!("seal_in_place failed: {:?}", e))?;
+        Ok((tls_plaintext, tag))
+    }
+}
+
+/// Traffic keys for both directions, along with the transcript they were derived from.
+pub trait TlsEncryptDecrypt: TlsEncrypt {
     fn add_transcript(&mut self, data: &[u8]) {
         debug!(
             "transcript_hash_context.update ({:?}): {:02X?}...",
             data.len(),
-            &data[..10]
+            &data[..data.len().min(10)]
         );
         self.transcript_hash_context_mut().update(data);
         let hash = self.transcript_hash_context().clone().finish();
@@ -95,35 +195,28 @@ pub trait TlsEncryptDecrypt {
     }
 
     fn get_read_seq_num_and_incr(&mut self) -> u64;
-    fn get_write_seq_num_and_incr(&mut self) -> u64;
-    fn encryption_key(&self) -> &[u8];
-    fn encryption_iv(&self) -> &[u8];
 
     fn decryption_key(&self) -> &[u8];
     fn decryption_iv(&self) -> &[u8];
 
     fn client_traffic_secret(&self) -> &[u8];
+    fn server_traffic_secret(&self) -> &[u8];
     fn transcript_hash_context_mut(&mut self) -> &mut ring::digest::Context;
     fn transcript_hash_context(&self) -> &ring::digest::Context;
 
     fn get_verify_client_data(&self) -> anyhow::Result<Vec<u8>> {
+        self.get_verify_data(self.client_traffic_secret())
+    }
+
+    /// Expected verify_data of the server Finished, computed over the transcript up to
+    /// (but not including) that Finished message.
+    fn get_verify_server_data(&self) -> anyhow::Result<Vec<u8>> {
+        self.get_verify_data(self.server_traffic_secret())
+    }
+
+    fn get_verify_data(&self, traffic_secret: &[u8]) -> anyhow::Result<Vec<u8>> {
         let digest = self.transcript_hash_context().clone().finish();
-        let finished_key =
-            crate::key_schedule::HKDF::new(self.client_traffic_secret()).expand_label(
-                &crate::key_schedule::HkdfLabel::new(32, "finished", b""),
-            )?;
-        // The verify_data field is an HMAC over the transcript hash using finished_key.
-        // The HMAC is computed as follows:
-        // HMAC(finished_key, transcript_hash)
-        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &finished_key);
-        let verify_data = ring::hmac::sign(&key, digest.as_ref());
-        debug!(
-            "verify_data: {:02X?} key: {:02X?} digest: {:02X?}",
-            verify_data.as_ref(),
-            finished_key,
-            digest.as_ref()
-        );
-        Ok(verify_data.as_ref().to_vec())
+        verify_data(self.cipher_suite(), traffic_secret, digest.as_ref())
     }
     fn decrypt_tls_encrypted<'a>(
         &mut self,
@@ -143,40 +236,16 @@ pub trait TlsEncryptDecrypt {
         if self.decryption_key().is_empty() {
             return Err(anyhow::anyhow!("server_write_key is empty"));
         }
-        let server_write_key = UnboundKey::new(&ring::aead::AES_128_GCM, self.decryption_key())
+        let server_write_key = UnboundKey::new(self.cipher_suite().aead, self.decryption_key())
             .map_err(|e| anyhow::anyhow!("UnboundKey failed: {:?}", e))?;
         let aad = ring::aead::Aad::from(&hdr_buf);
         ring::aead::LessSafeKey::new(server_write_key)
             .open_in_place(nonce, aad, tls_encrypted_content)
-            .map_err(|e| anyhow::anyhow!("open_in_place failed: {:?}", e))
-    }
-
-    fn encrypt_tls_plaintext<'a>(
-        &mut self,
-        hdr_buf: [u8; 5],
-        tls_plaintext: &'a mut [u8],
-    ) -> anyhow::Result<(&'a [u8], ring::aead::Tag)> {
-        let seq_num = self.get_write_seq_num_and_incr();
-        let nonce = derive_nonce(self.encryption_iv(), seq_num);
-        debug!(
-            "[encrypt_tls_plaintext] nonce: {:02X?}, key:{:02X?}, seq_num: {} tls_plaintext({}): {:02X?}",
-            nonce,
-            self.encryption_key(),
-            seq_num,
-            tls_plaintext.len(),
-            tls_plaintext
-        );
-        let nonce = ring::aead::Nonce::try_assume_unique_for_key(&nonce)
-            .map_err(|e| anyhow::anyhow!("try_assume_unique_for_key failed: {:?}", e))?;
-        if self.encryption_key().is_empty() {
-            return Err(anyhow::anyhow!("server_write_key is empty"));
-        }
-        let server_write_key = UnboundKey::new(&ring::aead::AES_128_GCM, self.encryption_key())
-            .map_err(|e| anyhow::anyhow!("UnboundKey failed: {:?}", e))?;
-        let aad = ring::aead::Aad::from(&hdr_buf);
-        let tag = ring::aead::LessSafeKey::new(server_write_key)
-            .seal_in_place_separate_tag(nonce, aad, tls_plaintext)
-            .map_err(|e| anyhow::anyhow!("seal_in_place failed: {:?}", e))?;
-        Ok((tls_plaintext, tag))
+            .map_err(|e| {
+                crate::alert::fatal(
+                    tls_parser::TlsAlertDescription::BadRecordMac,
+                    format!("open_in_place failed: {:?}", e),
+                )
+            })
     }
 }
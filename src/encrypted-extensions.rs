@@ -0,0 +1,96 @@
+use crate::alert;
+use crate::codec;
+use tls_parser::nom;
+use tls_parser::nom::multi::length_data;
+use tls_parser::nom::number::complete::{be_u8, be_u16};
+use tls_parser::{TlsAlertDescription, TlsExtensionType};
+
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+
+/// What the server negotiated in EncryptedExtensions.
+#[derive(Debug, Default)]
+pub(crate) struct EncryptedExtensions {
+    pub(crate) alpn_protocol: Option<Vec<u8>>,
+    /// The server accepted the 0-RTT data we sent.
+    pub(crate) early_data_accepted: bool,
+}
+
+pub(crate) fn parse_encrypted_extensions(
+    msg: &[u8],
+    offered_alpn_protocols: &[Vec<u8>],
+    early_data_offered: bool,
+) -> anyhow::Result<EncryptedExtensions> {
+    let (msg_type, body) = codec::split_handshake(msg)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    if msg_type != ENCRYPTED_EXTENSIONS {
+        return Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            format!("expected EncryptedExtensions, got handshake type {}", msg_type),
+        ));
+    }
+    let extensions = codec::parse_extensions(body)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    let alpn = codec::find_extension(
+        &extensions,
+        TlsExtensionType::ApplicationLayerProtocolNegotiation,
+    );
+    let alpn_protocol = match alpn {
+        Some(data) => Some(parse_selected_protocol(data, offered_alpn_protocols)?),
+        None => None,
+    };
+    let early_data = codec::find_extension(&extensions, TlsExtensionType::EarlyData);
+    let early_data_accepted = match early_data {
+        Some([]) if early_data_offered => true,
+        Some([]) => {
+            return Err(alert::fatal(
+                TlsAlertDescription::UnsupportedExtension,
+                "server accepted early data we did not send",
+            ));
+        }
+        Some(_) => {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                "malformed EncryptedExtensions early_data",
+            ));
+        }
+        None => false,
+    };
+    Ok(EncryptedExtensions {
+        alpn_protocol,
+        early_data_accepted,
+    })
+}
+
+/// The server's ALPN extension carries a ProtocolNameList with exactly one name, which must
+/// be one we offered.
+fn parse_selected_protocol(data: &[u8], offered: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    if offered.is_empty() {
+        return Err(alert::fatal(
+            TlsAlertDescription::UnsupportedExtension,
+            "server sent ALPN but we did not offer any protocols",
+        ));
+    }
+    let (rest, list) = length_data(be_u16)(data).map_err(|e: nom::Err<nom::error::Error<_>>| {
+        alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e))
+    })?;
+    let (list_rest, protocol) =
+        length_data(be_u8)(list).map_err(|e: nom::Err<nom::error::Error<_>>| {
+            alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e))
+        })?;
+    if !rest.is_empty() || !list_rest.is_empty() || protocol.is_empty() {
+        return Err(alert::fatal(
+            TlsAlertDescription::DecodeError,
+            "server ALPN must select exactly one protocol",
+        ));
+    }
+    if !offered.iter().any(|offered| offered.as_slice() == protocol) {
+        return Err(alert::fatal(
+            TlsAlertDescription::NoApplicationProtocol,
+            format!(
+                "server selected an application protocol we did not offer: {:?}",
+                String::from_utf8_lossy(protocol)
+            ),
+        ));
+    }
+    Ok(protocol.to_vec())
+}
@@ -0,0 +1,51 @@
+use crate::alert;
+use tls_parser::TlsAlertDescription;
+
+/// Largest handshake message we accept. Certificate chains are the only messages that get
+/// anywhere near this.
+const MAX_HANDSHAKE_MESSAGE_LEN: usize = 0xFFFF;
+
+/// Collects handshake record payloads and splits them into complete handshake messages,
+/// independent of how the peer fragmented or coalesced them into records.
+#[derive(Default)]
+pub(crate) struct HandshakeBuffer {
+    pending: Vec<u8>,
+}
+
+impl HandshakeBuffer {
+    pub(crate) fn push(&mut self, fragment: &[u8]) {
+        self.pending.extend_from_slice(fragment);
+    }
+
+    /// Takes the next complete message, header included, so the caller can add its exact
+    /// bytes to the transcript. Returns `None` until enough records have been pushed.
+    pub(crate) fn next_message(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(&[msg_type, a, b, c]) = self.pending.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes([0, a, b, c]) as usize;
+        if len > MAX_HANDSHAKE_MESSAGE_LEN {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                format!("handshake message type {} too large: {} bytes", msg_type, len),
+            ));
+        }
+        if self.pending.len() < 4 + len {
+            return Ok(None);
+        }
+        let rest = self.pending.split_off(4 + len);
+        Ok(Some(std::mem::replace(&mut self.pending, rest)))
+    }
+
+    /// Handshake messages must not span a key change (RFC 8446, section 5.1).
+    pub(crate) fn expect_key_change(&self) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(alert::fatal(
+                TlsAlertDescription::UnexpectedMessage,
+                "handshake data left over at a key change",
+            ))
+        }
+    }
+}
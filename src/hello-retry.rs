@@ -0,0 +1,74 @@
+use crate::alert;
+use crate::codec;
+use tls_parser::nom;
+use tls_parser::nom::multi::length_data;
+use tls_parser::nom::number::complete::be_u16;
+use tls_parser::{NamedGroup, TlsAlertDescription, TlsExtensionType};
+
+/// SHA-256("HelloRetryRequest"), sent as ServerHello.random to mark a HelloRetryRequest.
+const HELLO_RETRY_REQUEST_RANDOM: [u8; 32] = [
+    0xCF, 0x21, 0xAD, 0x74, 0xE5, 0x9A, 0x61, 0x11, 0xBE, 0x1D, 0x8C, 0x02, 0x1E, 0x65, 0xB8, 0x91,
+    0xC2, 0xA2, 0x11, 0x16, 0x7A, 0xBB, 0x8C, 0x5E, 0x07, 0x9E, 0x09, 0xE2, 0xC8, 0xA8, 0x33, 0x9C,
+];
+
+const TLS13: u16 = 0x0304;
+
+#[derive(Debug)]
+pub(crate) struct HelloRetryRequest<'a> {
+    pub(crate) cipher_suite: u16,
+    pub(crate) selected_group: Option<NamedGroup>,
+    pub(crate) cookie: Option<&'a [u8]>,
+}
+
+/// Checks the random of a raw ServerHello handshake message.
+pub(crate) fn is_hello_retry_request(server_hello: &[u8]) -> bool {
+    server_hello.first() == Some(&2)
+        && server_hello.get(6..38) == Some(&HELLO_RETRY_REQUEST_RANDOM[..])
+}
+
+pub(crate) fn parse_hello_retry_request(msg: &[u8]) -> anyhow::Result<HelloRetryRequest<'_>> {
+    let (_, body) = codec::split_handshake(msg)?;
+    let (i, cipher_suite) = codec::parse_server_hello_fixed_fields(body)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e)))?;
+    let extensions = codec::parse_extensions(i)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+
+    let supported_version = codec::find_extension(&extensions, TlsExtensionType::SupportedVersions);
+    if supported_version != Some(&TLS13.to_be_bytes()[..]) {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            "HelloRetryRequest did not select TLS 1.3",
+        ));
+    }
+    let selected_group = match codec::find_extension(&extensions, TlsExtensionType::KeyShare) {
+        Some(&[hi, lo]) => Some(NamedGroup(u16::from_be_bytes([hi, lo]))),
+        Some(_) => {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                "malformed HelloRetryRequest key_share",
+            ));
+        }
+        None => None,
+    };
+    let cookie = match codec::find_extension(&extensions, TlsExtensionType::Cookie) {
+        Some(data) => {
+            let (_, cookie) =
+                length_data(be_u16)(data).map_err(|e: nom::Err<nom::error::Error<_>>| {
+                    alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e))
+                })?;
+            Some(cookie)
+        }
+        None => None,
+    };
+    if selected_group.is_none() && cookie.is_none() {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            "HelloRetryRequest would not change the ClientHello",
+        ));
+    }
+    Ok(HelloRetryRequest {
+        cipher_suite,
+        selected_group,
+        cookie,
+    })
+}
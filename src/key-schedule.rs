@@ -1,9 +1,16 @@
-use crate::enc_dec::TlsEncryptDecrypt;
+use crate::alert;
+use crate::cipher_suite::{self, CipherSuite};
+use crate::enc_dec::{self, TlsEncryptDecrypt};
+use crate::key_share::KeyShare;
+use crate::resumption::SessionTicket;
 use log::{debug, info};
-use ring::agreement::EphemeralPrivateKey;
-use ring::digest::SHA256;
 use ring::hkdf;
 use ring::hkdf::Salt;
+use ring::rand::SecureRandom;
+use tls_parser::{NamedGroup, TlsAlertDescription};
+
+/// Handshake type of the synthetic message that replaces ClientHello1 after a HelloRetryRequest.
+const MESSAGE_HASH: u8 = 254;
 
 pub(crate) struct HkdfLabel<'a> {
     length: u16,
@@ -40,19 +47,19 @@ impl hkdf::KeyType for CustomKeyType {
 }
 
 impl HKDF {
-    pub fn extract(shared_secret: &[u8], salt: &[u8]) -> Self {
+    pub fn extract(cipher_suite: &CipherSuite, shared_secret: &[u8], salt: &[u8]) -> Self {
         debug!(
             "extract shared_secret: {:02X?}, salt: {:02X?}",
             shared_secret, salt
         );
-        let salt = Salt::new(hkdf::HKDF_SHA256, salt);
+        let salt = Salt::new(*cipher_suite.hkdf, salt);
         let prk = salt.extract(shared_secret);
         Self { prk }
     }
 
-    pub fn new(secret: &[u8]) -> Self {
+    pub fn new(cipher_suite: &CipherSuite, secret: &[u8]) -> Self {
         debug!("new secret: {:02X?}", secret);
-        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret);
+        let prk = hkdf::Prk::new_less_safe(*cipher_suite.hkdf, secret);
         Self { prk }
     }
     pub fn expand_label(&self, label: &HkdfLabel) -> anyhow::Result<Vec<u8>> {
@@ -72,23 +79,48 @@ impl HKDF {
         Ok(output_keymaterial)
     }
 
-    pub fn derive_empty_secret() -> anyhow::Result<Vec<u8>> {
-        let hkdf = HKDF::extract(&[0u8; 32], &[0u8; 32]);
-        let empty_hash = ring::digest::digest(&SHA256, b"");
+    /// The early secret, extracted from the PSK or from zeros when there is none.
+    pub fn early_secret(cipher_suite: &CipherSuite, psk: Option<&[u8]>) -> Self {
+        let zeros = vec![0u8; cipher_suite.hash_len()];
+        HKDF::extract(cipher_suite, psk.unwrap_or(&zeros), &zeros)
+    }
+
+    /// binder_key for a resumption PSK: Derive-Secret(early_secret, "res binder", "").
+    pub fn binder_key(cipher_suite: &CipherSuite, psk: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let empty_hash = ring::digest::digest(cipher_suite.hash, b"");
+        HKDF::early_secret(cipher_suite, Some(psk)).expand_label(&HkdfLabel::new(
+            cipher_suite.hash_len() as u16,
+            "res binder",
+            empty_hash.as_ref(),
+        ))
+    }
+
+    /// Derive-Secret(early_secret, "derived", ""), the salt for the handshake secret.
+    pub fn derive_empty_secret(
+        cipher_suite: &CipherSuite,
+        psk: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let hkdf = HKDF::early_secret(cipher_suite, psk);
+        let empty_hash = ring::digest::digest(cipher_suite.hash, b"");
         debug!("empty_hash: {:02X?}", empty_hash);
-        let label = HkdfLabel::new(32, "derived", empty_hash.as_ref());
+        let label = HkdfLabel::new(cipher_suite.hash_len() as u16, "derived", empty_hash.as_ref());
         hkdf.expand_label(&label)
     }
 
-    pub fn derive_master_secret(handshake_secret: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let hkdf = HKDF::extract(handshake_secret, &[0u8; 32]);
-        let transcript_hash = ring::digest::digest(&SHA256, b"");
-        let label = HkdfLabel::new(32, "derived", transcript_hash.as_ref());
-        hkdf.expand_label(&label)
+    /// Derives the AEAD key and IV from a traffic secret.
+    pub fn derive_key_and_iv(
+        cipher_suite: &CipherSuite,
+        traffic_secret: &[u8],
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let hkdf = HKDF::new(cipher_suite, traffic_secret);
+        let key = hkdf.expand_label(&HkdfLabel::new(cipher_suite.key_len() as u16, "key", b""))?;
+        let iv = hkdf.expand_label(&HkdfLabel::new(cipher_suite.iv_len() as u16, "iv", b""))?;
+        Ok((key, iv))
     }
 }
 
 pub(crate) struct ApplicationKeySchedule {
+    pub(crate) cipher_suite: &'static CipherSuite,
     pub(crate) server_application_traffic_secret: Vec<u8>,
     pub(crate) client_application_traffic_secret: Vec<u8>,
     pub(crate) server_write_key: Vec<u8>,
@@ -96,22 +128,72 @@ pub(crate) struct ApplicationKeySchedule {
     pub(crate) client_write_key: Vec<u8>,
     pub(crate) client_write_iv: Vec<u8>,
     pub(crate) transcript_hash_context: ring::digest::Context,
+    pub(crate) resumption_master_secret: Vec<u8>,
     pub(crate) read_seq_num: u64,
     pub(crate) write_seq_num: u64,
 }
 
+impl ApplicationKeySchedule {
+    /// application_traffic_secret_N+1 (RFC 8446, section 7.2).
+    fn next_traffic_secret(&self, secret: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let hash_len = self.cipher_suite.hash_len() as u16;
+        HKDF::new(self.cipher_suite, secret).expand_label(&HkdfLabel::new(
+            hash_len,
+            "traffic upd",
+            b"",
+        ))
+    }
+
+    /// Moves the server's write direction to the next generation after its KeyUpdate.
+    pub fn update_read_secret(&mut self) -> anyhow::Result<()> {
+        self.server_application_traffic_secret =
+            self.next_traffic_secret(&self.server_application_traffic_secret)?;
+        (self.server_write_key, self.server_write_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.server_application_traffic_secret)?;
+        self.read_seq_num = 0;
+        info!("updated server application traffic keys");
+        Ok(())
+    }
+
+    /// Moves our write direction to the next generation after sending a KeyUpdate.
+    pub fn update_write_secret(&mut self) -> anyhow::Result<()> {
+        self.client_application_traffic_secret =
+            self.next_traffic_secret(&self.client_application_traffic_secret)?;
+        (self.client_write_key, self.client_write_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.client_application_traffic_secret)?;
+        self.write_seq_num = 0;
+        info!("updated client application traffic keys");
+        Ok(())
+    }
+}
+
+/// Keys for 0-RTT data, derived from the PSK and the ClientHello. Only the client writes
+/// with them.
+pub(crate) struct EarlyKeySchedule {
+    pub(crate) cipher_suite: &'static CipherSuite,
+    pub(crate) client_write_key: Vec<u8>,
+    pub(crate) client_write_iv: Vec<u8>,
+    pub(crate) write_seq_num: u64,
+}
+
 pub(crate) struct HandshakeKeySchedule {
+    pub(crate) cipher_suite: &'static CipherSuite,
+    cipher_suite_selected: bool,
+    client_hello: Vec<u8>,
     pub(crate) transcript_hash_context: ring::digest::Context,
+    /// The suite and PSK of the ticket offered in the ClientHello, if any.
+    offered_psk: Option<(&'static CipherSuite, Vec<u8>)>,
+    psk_accepted: bool,
     handshake_secret: Vec<u8>,
     master_secret: HKDF,
-    server_handshake_traffic_secret: Vec<u8>,
+    pub(crate) server_handshake_traffic_secret: Vec<u8>,
     pub(crate) client_handshake_traffic_secret: Vec<u8>,
     pub(crate) client_write_key: Vec<u8>,
     pub(crate) client_write_iv: Vec<u8>,
     pub(crate) server_write_key: Vec<u8>,
     pub(crate) server_write_iv: Vec<u8>,
-    my_private_key: Option<EphemeralPrivateKey>,
-    my_public_key: ring::agreement::PublicKey,
+    /// Our key shares, one per group offered in the ClientHello key_share.
+    key_shares: Vec<KeyShare>,
     server_application_traffic_secret: Vec<u8>,
     client_application_traffic_secret: Vec<u8>,
     pub(crate) read_seq_num: u64,
@@ -120,12 +202,10 @@ pub(crate) struct HandshakeKeySchedule {
 
 impl HandshakeKeySchedule {
     pub fn into_application_key_schedule(self) -> anyhow::Result<ApplicationKeySchedule> {
-        let hkdf_for_app_write = HKDF::new(self.client_application_traffic_secret.as_ref());
-        let app_write_key = hkdf_for_app_write.expand_label(&HkdfLabel::new(16, "key", b""))?;
-        let app_write_iv = hkdf_for_app_write.expand_label(&HkdfLabel::new(12, "iv", b""))?;
-        let hkdf_for_app_read = HKDF::new(self.server_application_traffic_secret.as_ref());
-        let app_read_key = hkdf_for_app_read.expand_label(&HkdfLabel::new(16, "key", b""))?;
-        let app_read_iv = hkdf_for_app_read.expand_label(&HkdfLabel::new(12, "iv", b""))?;
+        let (app_write_key, app_write_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.client_application_traffic_secret)?;
+        let (app_read_key, app_read_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.server_application_traffic_secret)?;
         info!(
             "\napp_write_key: {:02X?}\
              \napp_write_iv: {:02X?}\
@@ -133,7 +213,15 @@ impl HandshakeKeySchedule {
              \napp_read_iv: {:02X?}",
             app_write_key, app_write_iv, app_read_key, app_read_iv
         );
+        // The transcript now runs through the client Finished.
+        let transcript_hash = self.transcript_hash_context.clone().finish();
+        let resumption_master_secret = self.master_secret.expand_label(&HkdfLabel::new(
+            self.cipher_suite.hash_len() as u16,
+            "res master",
+            transcript_hash.as_ref(),
+        ))?;
         Ok(ApplicationKeySchedule {
+            cipher_suite: self.cipher_suite,
             server_application_traffic_secret: self.server_application_traffic_secret,
             client_application_traffic_secret: self.client_application_traffic_secret,
             server_write_key: app_read_key,
@@ -141,30 +229,40 @@ impl HandshakeKeySchedule {
             client_write_key: app_write_key,
             client_write_iv: app_write_iv,
             transcript_hash_context: self.transcript_hash_context,
+            resumption_master_secret,
             read_seq_num: 0,
             write_seq_num: 0,
         })
     }
 
-    pub fn new() -> anyhow::Result<Self> {
-        let transcript_hash_context = ring::digest::Context::new(&ring::digest::SHA256);
+    /// Starts with the preferred cipher suite; `select_cipher_suite` switches the transcript
+    /// hash once the server has chosen. A key share is generated for each of
+    /// `key_share_groups` with randomness from `rng`.
+    pub fn new(key_share_groups: &[NamedGroup], rng: &dyn SecureRandom) -> anyhow::Result<Self> {
+        let cipher_suite = cipher_suite::preferred_cipher_suites()[0];
+        let transcript_hash_context = ring::digest::Context::new(cipher_suite.hash);
         let handshake_secret = Vec::new();
         let server_handshake_traffic_secret = Vec::new();
-        let rng = ring::rand::SystemRandom::new();
-        let my_private_key = EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .map_err(|e| anyhow::anyhow!("generate failed: {:?}", e))?;
-        let my_public_key = my_private_key
-            .compute_public_key()
-            .map_err(|e| anyhow::anyhow!("compute_public_key failed: {:?}", e))?;
+        if key_share_groups.is_empty() {
+            anyhow::bail!("no groups to send key shares for");
+        }
+        let key_shares = key_share_groups
+            .iter()
+            .map(|&group| KeyShare::generate(group, rng))
+            .collect::<anyhow::Result<Vec<_>>>()?;
         Ok(Self {
+            cipher_suite,
+            cipher_suite_selected: false,
+            client_hello: Vec::new(),
             transcript_hash_context,
+            offered_psk: None,
+            psk_accepted: false,
             handshake_secret,
             server_handshake_traffic_secret,
-            my_private_key: Some(my_private_key),
-            my_public_key,
+            key_shares,
             server_write_key: Vec::new(),
             server_write_iv: Vec::new(),
-            master_secret: HKDF::extract(&[0u8; 32], &[0u8; 32]),
+            master_secret: HKDF::extract(cipher_suite, &[], &[]),
             server_application_traffic_secret: Vec::new(),
             client_application_traffic_secret: Vec::new(),
             client_handshake_traffic_secret: Vec::new(),
@@ -174,18 +272,179 @@ impl HandshakeKeySchedule {
             write_seq_num: 0,
         })
     }
-    pub fn update_handshake_secret(&mut self, server_pub: &[u8]) -> anyhow::Result<()> {
-        let public_key =
-            ring::agreement::UnparsedPublicKey::new(&ring::agreement::X25519, server_pub);
-        ring::agreement::agree_ephemeral(
-            self.my_private_key.take().unwrap(),
-            &public_key,
-            |key_material| {
-                self.handshake_secret.extend_from_slice(key_material);
-            },
-        )
-        .map_err(|e| anyhow::anyhow!("agree_ephemeral failed: {:?}", e))?;
+    /// Prepares to offer `ticket` in the first ClientHello. The transcript hash switches to
+    /// the ticket's hash function, which the binder is computed with.
+    pub fn offer_psk(&mut self, ticket: &SessionTicket) {
+        self.cipher_suite = ticket.cipher_suite;
+        self.transcript_hash_context = ring::digest::Context::new(ticket.cipher_suite.hash);
+        self.offered_psk = Some((ticket.cipher_suite, ticket.psk.clone()));
+    }
+
+    /// A PSK can only be offered while its hash matches the transcript hash, which a
+    /// HelloRetryRequest may have changed.
+    pub fn can_offer_psk(&self) -> bool {
+        self.offered_psk
+            .as_ref()
+            .is_some_and(|(suite, _)| suite.hash == self.cipher_suite.hash)
+    }
+
+    /// Computes the PSK binder over the transcript so far plus the ClientHello truncated
+    /// before its binders list (RFC 8446, section 4.2.11.2).
+    pub fn psk_binder(&self, truncated_client_hello: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (cipher_suite, psk) = self
+            .offered_psk
+            .as_ref()
+            .ok_or(anyhow::anyhow!("no PSK offered"))?;
+        let binder_key = HKDF::binder_key(cipher_suite, psk)?;
+        let mut transcript_hash_context = self.transcript_hash_context.clone();
+        transcript_hash_context.update(truncated_client_hello);
+        let transcript_hash = transcript_hash_context.finish();
+        // The binder is computed like the verify_data of a Finished, keyed with binder_key.
+        enc_dec::verify_data(cipher_suite, &binder_key, transcript_hash.as_ref())
+    }
+
+    /// Derives client_early_traffic_secret from the offered PSK over the ClientHello just
+    /// added to the transcript.
+    pub fn early_key_schedule(&self) -> anyhow::Result<EarlyKeySchedule> {
+        let (cipher_suite, psk) = self
+            .offered_psk
+            .as_ref()
+            .ok_or(anyhow::anyhow!("no PSK offered"))?;
+        let transcript_hash = self.transcript_hash_context.clone().finish();
+        let client_early_traffic_secret = HKDF::early_secret(cipher_suite, Some(psk))
+            .expand_label(&HkdfLabel::new(
+                cipher_suite.hash_len() as u16,
+                "c e traffic",
+                transcript_hash.as_ref(),
+            ))?;
+        let (client_write_key, client_write_iv) =
+            HKDF::derive_key_and_iv(cipher_suite, &client_early_traffic_secret)?;
+        debug!("client_early_traffic_secret: {:02X?}", client_early_traffic_secret);
+        Ok(EarlyKeySchedule {
+            cipher_suite,
+            client_write_key,
+            client_write_iv,
+            write_seq_num: 0,
+        })
+    }
+
+    /// Records that the ServerHello selected our PSK, whose hash must match the suite.
+    pub fn accept_psk(&mut self) -> anyhow::Result<()> {
+        if !self.can_offer_psk() {
+            return Err(alert::fatal(
+                TlsAlertDescription::IllegalParameter,
+                "server selected a PSK we did not offer for this cipher suite",
+            ));
+        }
+        self.psk_accepted = true;
+        Ok(())
+    }
+
+    pub fn psk_accepted(&self) -> bool {
+        self.psk_accepted
+    }
+
+    fn accepted_psk(&self) -> Option<&[u8]> {
+        match &self.offered_psk {
+            Some((_, psk)) if self.psk_accepted => Some(psk),
+            _ => None,
+        }
+    }
+
+    pub fn add_client_hello(&mut self, client_hello: &[u8]) {
+        self.client_hello = client_hello.to_vec();
+        self.add_transcript(client_hello);
+    }
+
+    /// Fixes the cipher suite chosen in a HelloRetryRequest or ServerHello. Until then the
+    /// transcript only holds ClientHello1, so it is rehashed if the hash function changes.
+    pub fn select_cipher_suite(
+        &mut self,
+        cipher_suite: &'static CipherSuite,
+    ) -> anyhow::Result<()> {
+        if self.cipher_suite_selected {
+            if self.cipher_suite.id != cipher_suite.id {
+                return Err(alert::fatal(
+                    TlsAlertDescription::IllegalParameter,
+                    "ServerHello cipher suite differs from HelloRetryRequest",
+                ));
+            }
+            return Ok(());
+        }
+        if self.cipher_suite.hash != cipher_suite.hash {
+            self.transcript_hash_context = ring::digest::Context::new(cipher_suite.hash);
+            let client_hello = std::mem::take(&mut self.client_hello);
+            self.add_transcript(&client_hello);
+            self.client_hello = client_hello;
+        }
+        self.cipher_suite = cipher_suite;
+        self.cipher_suite_selected = true;
+        Ok(())
+    }
+
+    /// Replaces ClientHello1 in the transcript with its `message_hash` and adds the
+    /// HelloRetryRequest. If the server asked for another of our `supported_groups`, the key
+    /// shares are replaced by a single one for that group, generated with `rng`.
+    pub fn on_hello_retry_request(
+        &mut self,
+        hello_retry_request: &[u8],
+        selected_group: Option<NamedGroup>,
+        supported_groups: &[NamedGroup],
+        rng: &dyn SecureRandom,
+    ) -> anyhow::Result<()> {
+        if let Some(group) = selected_group {
+            if self.key_share_for(group).is_some() || !supported_groups.contains(&group) {
+                return Err(alert::fatal(
+                    TlsAlertDescription::IllegalParameter,
+                    format!("HelloRetryRequest selected unacceptable group {:?}", group),
+                ));
+            }
+            self.key_shares = vec![KeyShare::generate(group, rng)?];
+            info!("regenerated key share for {:?}", group);
+        }
+        let client_hello_hash = self.transcript_hash_context.clone().finish();
+        let mut transcript_hash_context = ring::digest::Context::new(self.cipher_suite.hash);
+        let hash_len = client_hello_hash.as_ref().len() as u8;
+        transcript_hash_context.update(&[MESSAGE_HASH, 0, 0, hash_len]);
+        transcript_hash_context.update(client_hello_hash.as_ref());
+        self.transcript_hash_context = transcript_hash_context;
+        self.add_transcript(hello_retry_request);
+        Ok(())
+    }
+
+    /// Completes the key exchange for the group the server picked, which must be one we sent
+    /// a share for.
+    pub fn update_handshake_secret(
+        &mut self,
+        group: NamedGroup,
+        server_pub: &[u8],
+    ) -> anyhow::Result<()> {
+        let Some(index) = self.key_share_for(group) else {
+            return Err(alert::fatal(
+                TlsAlertDescription::IllegalParameter,
+                format!("server key share for {:?}, which we did not send", group),
+            ));
+        };
+        let key_share = self.key_shares.swap_remove(index);
+        self.key_shares.clear();
+        self.handshake_secret = key_share.agree(server_pub)?;
         info!("handshake_secret: {:02X?}", self.handshake_secret);
+        self.derive_handshake_traffic_secrets()
+    }
+
+    /// For psk_ke, where the server sends no key share and the (EC)DHE input is all zeros.
+    pub fn update_handshake_secret_psk_only(&mut self) -> anyhow::Result<()> {
+        if self.accepted_psk().is_none() {
+            return Err(alert::fatal(
+                TlsAlertDescription::MissingExtension,
+                "ServerHello has neither key_share nor pre_shared_key",
+            ));
+        }
+        self.handshake_secret = vec![0u8; self.cipher_suite.hash_len()];
+        self.derive_handshake_traffic_secrets()
+    }
+
+    fn derive_handshake_traffic_secrets(&mut self) -> anyhow::Result<()> {
         self.derive_server_handshake_traffic_secret()?;
         self.derive_client_handshake_traffic_secret()?;
         self.derive_server_write_key_and_iv()?;
@@ -193,8 +452,16 @@ impl HandshakeKeySchedule {
         Ok(())
     }
 
-    pub fn get_client_public_key(&self) -> Vec<u8> {
-        Vec::from(self.my_public_key.as_ref())
+    /// The group and public key of each of our key shares, for the ClientHello.
+    pub fn client_key_shares(&self) -> Vec<(NamedGroup, Vec<u8>)> {
+        self.key_shares
+            .iter()
+            .map(|key_share| (key_share.group(), key_share.public_key().to_vec()))
+            .collect()
+    }
+
+    fn key_share_for(&self, group: NamedGroup) -> Option<usize> {
+        self.key_shares.iter().position(|key_share| key_share.group() == group)
     }
 
     pub fn on_server_finished(&mut self) -> anyhow::Result<()> {
@@ -202,20 +469,22 @@ impl HandshakeKeySchedule {
         self.derive_master_secret_and_traffic_secrets()
     }
     fn derive_master_secret_and_traffic_secrets(&mut self) -> anyhow::Result<()> {
-        let empty_hash = ring::digest::digest(&SHA256, b"");
+        let hash_len = self.cipher_suite.hash_len() as u16;
+        let empty_hash = ring::digest::digest(self.cipher_suite.hash, b"");
         let derived_secret = self
             .master_secret
-            .expand_label(&HkdfLabel::new(32, "derived", empty_hash.as_ref()))?;
+            .expand_label(&HkdfLabel::new(hash_len, "derived", empty_hash.as_ref()))?;
         let transcript_hash = self.transcript_hash_context.clone().finish();
         debug!(
             "\nderived_secret: {:02X?}\
              \ntranscript_hash: {:02X?}",
             derived_secret, transcript_hash.as_ref()
         );
-        let hkdf = HKDF::extract([0u8; 32].as_ref(), derived_secret.as_ref());
-        let label_server = HkdfLabel::new(32, "s ap traffic", transcript_hash.as_ref());
+        let zeros = vec![0u8; hash_len as usize];
+        let hkdf = HKDF::extract(self.cipher_suite, &zeros, derived_secret.as_ref());
+        let label_server = HkdfLabel::new(hash_len, "s ap traffic", transcript_hash.as_ref());
         self.server_application_traffic_secret = hkdf.expand_label(&label_server)?;
-        let label_client = HkdfLabel::new(32, "c ap traffic", transcript_hash.as_ref());
+        let label_client = HkdfLabel::new(hash_len, "c ap traffic", transcript_hash.as_ref());
         self.client_application_traffic_secret = hkdf.expand_label(&label_client)?;
         self.master_secret = hkdf;
         debug!(
@@ -228,10 +497,11 @@ impl HandshakeKeySchedule {
 
     fn derive_server_handshake_traffic_secret(&mut self) -> anyhow::Result<()> {
         let shared_secret = &self.handshake_secret;
-        let salt = HKDF::derive_empty_secret()?;
-        let hkdf = HKDF::extract(shared_secret, &salt);
+        let salt = HKDF::derive_empty_secret(self.cipher_suite, self.accepted_psk())?;
+        let hkdf = HKDF::extract(self.cipher_suite, shared_secret, &salt);
         let digest = self.transcript_hash_context.clone().finish();
-        let label = HkdfLabel::new(32, "s hs traffic", digest.as_ref());
+        let hash_len = self.cipher_suite.hash_len() as u16;
+        let label = HkdfLabel::new(hash_len, "s hs traffic", digest.as_ref());
         self.server_handshake_traffic_secret = hkdf.expand_label(&label)?;
         self.master_secret = hkdf;
         debug!(
@@ -245,10 +515,11 @@ impl HandshakeKeySchedule {
 
     fn derive_client_handshake_traffic_secret(&mut self) -> anyhow::Result<()> {
         let shared_secret = &self.handshake_secret;
-        let salt = HKDF::derive_empty_secret()?;
-        let hkdf = HKDF::extract(shared_secret, &salt);
+        let salt = HKDF::derive_empty_secret(self.cipher_suite, self.accepted_psk())?;
+        let hkdf = HKDF::extract(self.cipher_suite, shared_secret, &salt);
         let digest = self.transcript_hash_context.clone().finish();
-        let label = HkdfLabel::new(32, "c hs traffic", digest.as_ref());
+        let hash_len = self.cipher_suite.hash_len() as u16;
+        let label = HkdfLabel::new(hash_len, "c hs traffic", digest.as_ref());
         self.client_handshake_traffic_secret = hkdf.expand_label(&label)?;
         debug!(
             "\nclient_handshake_traffic_secret: {:02X?}\
@@ -260,24 +531,20 @@ impl HandshakeKeySchedule {
     }
 
     fn derive_server_write_key_and_iv(&mut self) -> anyhow::Result<()> {
-        let hkdf = HKDF::new(&self.server_handshake_traffic_secret);
-        let label_key = HkdfLabel::new(16, "key", b"");
-        let server_write_key = hkdf.expand_label(&label_key)?;
+        let (server_write_key, server_write_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.server_handshake_traffic_secret)?;
         self.server_write_key = server_write_key;
-        let label_iv = HkdfLabel::new(12, "iv", b"");
-        self.server_write_iv = hkdf.expand_label(&label_iv)?;
+        self.server_write_iv = server_write_iv;
         debug!("server_write_key: {:02X?}", self.server_write_key);
         debug!("server_write_iv: {:02X?}", self.server_write_iv);
         Ok(())
     }
 
     fn derive_client_write_key_and_iv(&mut self) -> anyhow::Result<()> {
-        let hkdf = HKDF::new(&self.client_handshake_traffic_secret);
-        let label_key = HkdfLabel::new(16, "key", b"");
-        let client_write_key = hkdf.expand_label(&label_key)?;
+        let (client_write_key, client_write_iv) =
+            HKDF::derive_key_and_iv(self.cipher_suite, &self.client_handshake_traffic_secret)?;
         self.client_write_key = client_write_key;
-        let label_iv = HkdfLabel::new(12, "iv", b"");
-        self.client_write_iv = hkdf.expand_label(&label_iv)?;
+        self.client_write_iv = client_write_iv;
         debug!("client_write_key: {:02X?}", self.client_write_key);
         debug!("client_write_iv: {:02X?}", self.client_write_iv);
         Ok(())
@@ -0,0 +1,226 @@
+use crate::alert;
+use ml_kem::kem::Decapsulate;
+use ml_kem::{EncodedSizeUser, KemCore, MlKem768};
+use ring::agreement::EphemeralPrivateKey;
+use ring::rand::SecureRandom;
+use std::num::NonZeroU32;
+use tls_parser::{NamedGroup, TlsAlertDescription};
+
+/// Hybrid of ML-KEM-768 and X25519 (draft-ietf-tls-ecdhe-mlkem).
+pub(crate) const X25519_MLKEM768: NamedGroup = NamedGroup(0x11EC);
+
+/// Groups we can do key exchange over, in our default order of preference.
+pub(crate) const SUPPORTED_GROUPS: [NamedGroup; 4] = [
+    X25519_MLKEM768,
+    NamedGroup::EcdhX25519,
+    NamedGroup::Secp256r1,
+    NamedGroup::Secp384r1,
+];
+
+const MLKEM768_CIPHERTEXT_LEN: usize = 1088;
+const X25519_PUBLIC_KEY_LEN: usize = 32;
+
+fn agreement_algorithm(group: NamedGroup) -> Option<&'static ring::agreement::Algorithm> {
+    match group {
+        NamedGroup::EcdhX25519 => Some(&ring::agreement::X25519),
+        NamedGroup::Secp256r1 => Some(&ring::agreement::ECDH_P256),
+        NamedGroup::Secp384r1 => Some(&ring::agreement::ECDH_P384),
+        _ => None,
+    }
+}
+
+/// Parses a group name as used in the IANA registry, e.g. "x25519" or "secp256r1".
+pub(crate) fn group_from_name(name: &str) -> Option<NamedGroup> {
+    match name {
+        "X25519MLKEM768" => Some(X25519_MLKEM768),
+        "x25519" => Some(NamedGroup::EcdhX25519),
+        "secp256r1" => Some(NamedGroup::Secp256r1),
+        "secp384r1" => Some(NamedGroup::Secp384r1),
+        _ => None,
+    }
+}
+
+enum PrivateKey {
+    Ecdhe(EphemeralPrivateKey),
+    X25519MlKem768 {
+        decapsulation_key: Box<<MlKem768 as KemCore>::DecapsulationKey>,
+        x25519: EphemeralPrivateKey,
+    },
+}
+
+/// An ephemeral key pair for one group, as offered in a ClientHello KeyShareEntry.
+pub(crate) struct KeyShare {
+    group: NamedGroup,
+    private_key: PrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl KeyShare {
+    /// Generates a key pair for `group`, taking all its randomness from `rng`.
+    pub(crate) fn generate(group: NamedGroup, rng: &dyn SecureRandom) -> anyhow::Result<Self> {
+        if group == X25519_MLKEM768 {
+            let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut MlKemRng(rng));
+            let (x25519, x25519_public_key) = generate_ecdhe(&ring::agreement::X25519, rng)?;
+            return Ok(Self {
+                group,
+                // The ML-KEM part comes first in this group's key_exchange.
+                public_key: [encapsulation_key.as_bytes().as_slice(), &x25519_public_key].concat(),
+                private_key: PrivateKey::X25519MlKem768 {
+                    decapsulation_key: Box::new(decapsulation_key),
+                    x25519,
+                },
+            });
+        }
+        let algorithm =
+            agreement_algorithm(group).ok_or(anyhow::anyhow!("unsupported group {:?}", group))?;
+        let (private_key, public_key) = generate_ecdhe(algorithm, rng)?;
+        Ok(Self {
+            group,
+            private_key: PrivateKey::Ecdhe(private_key),
+            public_key,
+        })
+    }
+
+    pub(crate) fn group(&self) -> NamedGroup {
+        self.group
+    }
+
+    /// The key_exchange field of our KeyShareEntry: the X25519 public key, the uncompressed
+    /// EC point, or the ML-KEM encapsulation key followed by the X25519 public key.
+    pub(crate) fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Computes the shared secret with the server's key_exchange, consuming the private key.
+    /// For X25519MLKEM768 that is the ML-KEM shared secret followed by the X25519 one.
+    pub(crate) fn agree(self, server_share: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let invalid_share = || {
+            alert::fatal(
+                TlsAlertDescription::IllegalParameter,
+                format!("invalid {:?} key share from server", self.group),
+            )
+        };
+        match self.private_key {
+            PrivateKey::Ecdhe(private_key) => {
+                agree_ecdhe(private_key, server_share).ok_or_else(invalid_share)
+            }
+            PrivateKey::X25519MlKem768 {
+                decapsulation_key,
+                x25519,
+            } => {
+                if server_share.len() != MLKEM768_CIPHERTEXT_LEN + X25519_PUBLIC_KEY_LEN {
+                    return Err(invalid_share());
+                }
+                let (ciphertext, x25519_share) = server_share.split_at(MLKEM768_CIPHERTEXT_LEN);
+                let ciphertext = ml_kem::Ciphertext::<MlKem768>::try_from(ciphertext)
+                    .map_err(|_| invalid_share())?;
+                let mlkem_secret = decapsulation_key
+                    .decapsulate(&ciphertext)
+                    .map_err(|_| invalid_share())?;
+                let x25519_secret = agree_ecdhe(x25519, x25519_share).ok_or_else(invalid_share)?;
+                Ok([mlkem_secret.as_slice(), &x25519_secret].concat())
+            }
+        }
+    }
+}
+
+/// The [`rand_core::Error`] code for a failed [`SecureRandom::fill`].
+const SECURE_RANDOM_FAILED: NonZeroU32 = NonZeroU32::new(rand_core::Error::CUSTOM_START).unwrap();
+
+/// Lets ML-KEM key generation draw from a ring [`SecureRandom`].
+struct MlKemRng<'a>(&'a dyn SecureRandom);
+
+impl rand_core::RngCore for MlKemRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        // ML-KEM key generation cannot fail, so like OsRng this panics without randomness.
+        self.try_fill_bytes(dest)
+            .expect("SecureRandom::fill failed");
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.0
+            .fill(dest)
+            .map_err(|_| rand_core::Error::from(SECURE_RANDOM_FAILED))
+    }
+}
+
+impl rand_core::CryptoRng for MlKemRng<'_> {}
+
+fn generate_ecdhe(
+    algorithm: &'static ring::agreement::Algorithm,
+    rng: &dyn SecureRandom,
+) -> anyhow::Result<(EphemeralPrivateKey, Vec<u8>)> {
+    let private_key = EphemeralPrivateKey::generate(algorithm, rng)
+        .map_err(|e| anyhow::anyhow!("generate failed: {:?}", e))?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|e| anyhow::anyhow!("compute_public_key failed: {:?}", e))?;
+    Ok((private_key, public_key.as_ref().to_vec()))
+}
+
+fn agree_ecdhe(private_key: EphemeralPrivateKey, server_share: &[u8]) -> Option<Vec<u8>> {
+    let public_key =
+        ring::agreement::UnparsedPublicKey::new(private_key.algorithm(), server_share);
+    ring::agreement::agree_ephemeral(private_key, &public_key, |key_material| {
+        key_material.to_vec()
+    })
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ml_kem::kem::Encapsulate;
+    use ring::rand::SystemRandom;
+
+    const MLKEM768_ENCAPSULATION_KEY_LEN: usize = 1184;
+
+    #[test]
+    fn x25519_mlkem768_secret_is_mlkem_then_x25519() {
+        let rng = SystemRandom::new();
+        let share = KeyShare::generate(X25519_MLKEM768, &rng).unwrap();
+        let (encapsulation_key, x25519_public_key) =
+            share.public_key().split_at(MLKEM768_ENCAPSULATION_KEY_LEN);
+        assert_eq!(x25519_public_key.len(), X25519_PUBLIC_KEY_LEN);
+
+        // What the server does with our share.
+        let encapsulation_key = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(
+            &encapsulation_key.try_into().unwrap(),
+        );
+        let (ciphertext, mlkem_secret) =
+            encapsulation_key.encapsulate(&mut MlKemRng(&rng)).unwrap();
+        let (x25519, server_x25519_public_key) =
+            generate_ecdhe(&ring::agreement::X25519, &rng).unwrap();
+        let x25519_secret = agree_ecdhe(x25519, x25519_public_key).unwrap();
+
+        let server_share = [ciphertext.as_slice(), &server_x25519_public_key].concat();
+        assert_eq!(
+            share.agree(&server_share).unwrap(),
+            [mlkem_secret.as_slice(), &x25519_secret].concat()
+        );
+    }
+
+    #[test]
+    fn x25519_mlkem768_share_of_wrong_length_is_illegal_parameter() {
+        let rng = SystemRandom::new();
+        for len in [
+            MLKEM768_CIPHERTEXT_LEN,
+            MLKEM768_CIPHERTEXT_LEN + X25519_PUBLIC_KEY_LEN + 1,
+        ] {
+            let share = KeyShare::generate(X25519_MLKEM768, &rng).unwrap();
+            let err = share.agree(&vec![1; len]).unwrap_err();
+            assert_eq!(
+                alert::alert_for(&err),
+                Some(TlsAlertDescription::IllegalParameter)
+            );
+        }
+    }
+}
@@ -1,78 +1,142 @@
+use crate::async_tls_stream::AsyncTlsStream;
+use crate::certificate_request::CertificateRequest;
+use crate::client_config::ClientConfig;
+use crate::client_identity::{CertificateEntry, ClientIdentity, IdentityStore};
+use crate::encrypted_extensions::EncryptedExtensions;
+use crate::handshake_buffer::HandshakeBuffer;
 use crate::key_schedule::{ApplicationKeySchedule, HandshakeKeySchedule};
-use der::Decode;
-use enc_dec::TlsEncryptDecrypt;
+use crate::resumption::{SessionCache, SessionTicket};
+use crate::tls_stream::TlsStream;
+use enc_dec::{TlsEncrypt, TlsEncryptDecrypt};
 use log::{debug, info};
-use signature::Signer;
-use std::io::{BufReader, Read, Write};
-use std::net::TcpStream;
-use tls_parser::KeyShare::{KeyShareClientHello, KeyShareServerHello};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use trust_store::TrustStore;
+use tls_parser::KeyShare::KeyShareClientHello;
 use tls_parser::KeyShareEntry;
 use tls_parser::NamedGroup;
-use tls_parser::TLS_AES_128_GCM_SHA256;
 use tls_parser::TlsEncrypted;
 use tls_parser::TlsEncryptedContent;
 use tls_parser::TlsMessageHandshake::Finished;
 use tls_parser::TlsPlaintext;
+use tls_parser::TlsRecordHeader;
 use tls_parser::TlsServerHelloContents;
-use tls_parser::nom::bytes::complete::take;
 use tls_parser::parse_tls_message_handshake;
-use tls_parser::{RawCertificate, TlsCertificateContents, TlsMessageHandshake, nom};
+use tls_parser::TlsMessageHandshake;
 use tls_parser::{Serialize, SignatureScheme};
-use tls_parser::{TlsExtension, TlsMessage, TlsRecordType};
-
+use tls_parser::{TlsAlertDescription, TlsExtension, TlsExtensionType, TlsMessage, TlsRecordType};
+
+mod alert;
+#[path = "async-tls-stream.rs"]
+mod async_tls_stream;
+#[path = "cert-verify.rs"]
+mod cert_verify;
+#[path = "certificate-request.rs"]
+mod certificate_request;
+#[path = "cipher-suite.rs"]
+mod cipher_suite;
+#[path = "client-config.rs"]
+mod client_config;
+#[path = "client-connection.rs"]
+mod client_connection;
+#[path = "client-identity.rs"]
+mod client_identity;
+mod codec;
 #[path = "enc-dec.rs"]
 mod enc_dec;
+#[path = "encrypted-extensions.rs"]
+mod encrypted_extensions;
+#[path = "handshake-buffer.rs"]
+mod handshake_buffer;
+#[path = "hello-retry.rs"]
+mod hello_retry;
 #[path = "key-schedule.rs"]
 mod key_schedule;
+#[path = "key-share.rs"]
+mod key_share;
+#[path = "post-handshake-auth.rs"]
+mod post_handshake_auth;
 mod tpm;
-
-struct TLSRecordReader<'a> {
-    buf_reader: BufReader<&'a TcpStream>,
-    vec: Vec<u8>,
+mod record;
+mod resumption;
+#[cfg(test)]
+#[path = "test-server.rs"]
+mod test_server;
+#[path = "tls-stream.rs"]
+mod tls_stream;
+#[path = "trust-store.rs"]
+mod trust_store;
+
+const DEFAULT_TRUST_STORE: &str = "server/ca.pem";
+const DEFAULT_SERVER_NAME: &str = "localhost";
+/// Sent to the server on each connection, as 0-RTT data when resuming.
+const CLIENT_MESSAGE: &[u8] = b"Hello from the client";
+const NEW_SESSION_TICKET: u8 = 4;
+const KEY_UPDATE: u8 = 24;
+const UPDATE_NOT_REQUESTED: u8 = 0;
+const UPDATE_REQUESTED: u8 = 1;
+/// An EndOfEarlyData handshake message, which has an empty body.
+const END_OF_EARLY_DATA: [u8; 4] = [5, 0, 0, 0];
+/// Largest TLSCiphertext.length allowed by RFC 8446, section 5.2.
+const MAX_CIPHERTEXT_LEN: usize = (1 << 14) + 256;
+
+/// A record's parsed header, its header bytes (the AEAD additional data) and its content.
+type Record = (TlsRecordHeader, [u8; 5], Vec<u8>);
+
+/// Splits the bytes received from the server into records. Handshake messages are
+/// reassembled across records in `handshake_buffer`.
+#[derive(Default)]
+struct TLSRecordReader {
+    incoming: Vec<u8>,
+    handshake_buffer: HandshakeBuffer,
 }
 
-impl<'a> TLSRecordReader<'a> {
-    pub fn new(stream: &'a TcpStream) -> Self {
-        TLSRecordReader {
-            buf_reader: BufReader::new(stream),
-            vec: Vec::new(),
-        }
-    }
-
-    pub fn read_tls_record(&mut self) -> anyhow::Result<TlsPlaintext> {
-        let (plaintext, _) = self.read_tls_record_with_vec()?;
-
-        Ok(plaintext)
+impl TLSRecordReader {
+    fn push(&mut self, data: &[u8]) {
+        self.incoming.extend_from_slice(data);
     }
 
-    pub fn read_tls_encrypted_record(&mut self) -> anyhow::Result<(TlsEncrypted, [u8; 5])> {
-        let mut hdr_buf = [0u8; 5];
-        self.buf_reader.read_exact(&mut hdr_buf)?;
-        let (_, hdr) = tls_parser::parse_tls_record_header(&hdr_buf)
-            .map_err(|e| anyhow::anyhow!("parse_tls_record_header failed: {:?}", e))?;
-        debug!("hdr: {:?}", hdr);
-        self.vec.resize(hdr.len as usize, 0);
-        self.buf_reader.read_exact(&mut self.vec)?;
-        let msg = TlsEncrypted {
-            hdr,
-            msg: TlsEncryptedContent { blob: &self.vec },
-        };
-        Ok((msg, hdr_buf))
-    }
-
-    pub fn read_tls_record_with_vec(&mut self) -> anyhow::Result<(TlsPlaintext, Vec<u8>)> {
-        self.vec.resize(5, 0);
-        self.buf_reader.read_exact(&mut self.vec)?;
-        let (_, hdr) = tls_parser::parse_tls_record_header(&self.vec)
-            .map_err(|e| anyhow::anyhow!("parse_tls_record_header failed: {:?}", e))?;
-        debug!("hdr: {:?}", hdr);
-        self.vec.resize(hdr.len as usize, 0);
-        self.buf_reader
-            .read_exact(&mut self.vec[0..hdr.len as usize])?;
-        let (_, msg) = tls_parser::parse_tls_record_with_header(&self.vec, &hdr)
-            .map_err(|e| anyhow::anyhow!("parse_tls_record_with_header failed: {:?}", e))?;
-        let plaintext = TlsPlaintext { hdr, msg };
-        Ok((plaintext, self.vec.clone()))
+    /// Takes the next complete record. Returns `None` until a whole record has been pushed.
+    ///
+    /// Until the server Finished, as `before_server_finished` tells, TLS 1.3 servers may send
+    /// a ChangeCipherSpec for middlebox compatibility, which is dropped. Any other
+    /// ChangeCipherSpec is an unexpected message (RFC 8446, section 5).
+    fn next_record(&mut self, before_server_finished: bool) -> anyhow::Result<Option<Record>> {
+        loop {
+            let Some(hdr_bytes) = self.incoming.get(..5) else {
+                return Ok(None);
+            };
+            let mut hdr_buf = [0u8; 5];
+            hdr_buf.copy_from_slice(hdr_bytes);
+            let (_, hdr) = tls_parser::parse_tls_record_header(&hdr_buf)
+                .map_err(|e| anyhow::anyhow!("parse_tls_record_header failed: {:?}", e))?;
+            debug!("hdr: {:?}", hdr);
+            let len = hdr.len as usize;
+            if len > MAX_CIPHERTEXT_LEN {
+                return Err(alert::fatal(
+                    TlsAlertDescription::RecordOverflow,
+                    format!("record too large: {} bytes", hdr.len),
+                ));
+            }
+            if self.incoming.len() < 5 + len {
+                return Ok(None);
+            }
+            let rest = self.incoming.split_off(5 + len);
+            let record = std::mem::replace(&mut self.incoming, rest);
+            if hdr.record_type != TlsRecordType::ChangeCipherSpec {
+                return Ok(Some((hdr, hdr_buf, record[5..].to_vec())));
+            }
+            if !before_server_finished || record[5..] != [1] {
+                return Err(alert::fatal(
+                    TlsAlertDescription::UnexpectedMessage,
+                    format!("unexpected ChangeCipherSpec record {:02X?}", &record[5..]),
+                ));
+            }
+            debug!("dropping ChangeCipherSpec record");
+        }
     }
 }
 
@@ -82,166 +146,485 @@ fn main() -> anyhow::Result<()> {
     }
     init_logger();
     info!("Application started");
+    let trust_store_path =
+        std::env::var("SEC_POC_TRUST_STORE").unwrap_or(DEFAULT_TRUST_STORE.to_string());
+    let trust_store = TrustStore::from_path(Path::new(&trust_store_path))?;
+    let server_name =
+        std::env::var("SEC_POC_SERVER_NAME").unwrap_or(DEFAULT_SERVER_NAME.to_string());
+    let mut config = ClientConfig::new(&server_name, trust_store)?;
+    if let Ok(port) = std::env::var("SEC_POC_PORT") {
+        config.port = port.parse()?;
+    }
+    if let Ok(alpn) = std::env::var("SEC_POC_ALPN") {
+        config.alpn_protocols = alpn.split(',').map(|p| p.as_bytes().to_vec()).collect();
+        if config.alpn_protocols.iter().any(|p| p.is_empty() || p.len() > 255) {
+            anyhow::bail!("ALPN protocol names must be 1 to 255 bytes: {:?}", alpn);
+        }
+    }
+    if let Ok(groups) = std::env::var("SEC_POC_GROUPS") {
+        config.groups = groups
+            .split(',')
+            .map(|name| key_share::group_from_name(name).ok_or(name))
+            .collect::<Result<_, _>>()
+            .map_err(|name| anyhow::anyhow!("unknown group {:?}", name))?;
+    }
+    if let Ok(key_shares) = std::env::var("SEC_POC_KEY_SHARES") {
+        config.key_shares = key_shares.parse()?;
+    }
+    if let Ok(interval) = std::env::var("SEC_POC_KEY_UPDATE_INTERVAL") {
+        config.key_update_interval = Some(interval.parse()?);
+    }
+    let config = Arc::new(config);
+    if std::env::var_os("SEC_POC_ASYNC").is_some() {
+        let (client_cert, signer) = tpm::TpmSigningThread::spawn()?;
+        let identities = client_identities(client_cert, signer)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(run_async_client(config, Arc::new(identities)));
+    }
     let (client_cert, signer) = tpm::get_client_cert()?;
-    // let google_addr = "8.8.8.8:443";
-    let local_addr = "localhost:4443";
-    // let stream = TcpStream::connect(google_addr)?;
-    let stream = TcpStream::connect(local_addr)?;
-    let mut tcp_writer = stream.try_clone()?;
-    let mut tls_record_reader = TLSRecordReader::new(&stream);
-    let key_schedule = key_schedule::HandshakeKeySchedule::new()?;
-    let mut key_schedule = start_handshake(
-        &mut tcp_writer,
-        &mut tls_record_reader,
-        key_schedule,
-        client_cert,
-        |data| Ok(signer.try_sign(data)?.signature),
-    )?;
-    info!("\n\n\n\n\nApplication finished\n\n\n\n\n");
-
-    let next_blob = read_tls_encrypted(&mut tls_record_reader, &mut key_schedule)?;
-    let app_string = unsafe { std::str::from_utf8(&next_blob[..next_blob.len() - 16])? };
-    info!("app_blob: {:02X?}, app_string: {}", next_blob, app_string);
+    let identities = Rc::new(client_identities(client_cert, signer)?);
+    // The second connection resumes with a ticket from the first and skips the TPM. Its
+    // message goes out as 0-RTT data if the ticket allows it.
+    for _ in 0..2 {
+        let mut tls_stream = TlsStream::connect_with_early_data(
+            config.clone(),
+            CLIENT_MESSAGE,
+            identities.clone(),
+            SystemRandom::new(),
+        )?;
+        info!(
+            "negotiated application protocol: {:?}, resumed: {}, early data: {:?}",
+            tls_stream.alpn_protocol().map(String::from_utf8_lossy),
+            tls_stream.resumed(),
+            tls_stream.early_data_status()
+        );
+        info!("\n\n\n\n\nApplication finished\n\n\n\n\n");
+
+        let mut reader = BufReader::new(&mut tls_stream);
+        let mut next_blob = Vec::new();
+        reader.read_until(0, &mut next_blob)?;
+        let app_string = std::str::from_utf8(&next_blob)?;
+        info!("app_blob: {:02X?}, app_string: {}", next_blob, app_string);
+        if tls_stream.early_data_status() != EarlyDataStatus::Accepted {
+            tls_stream.write_all(CLIENT_MESSAGE)?;
+        }
+        tls_stream.shutdown()?;
+    }
     Ok(())
 }
 
-fn start_handshake(
-    tcp_writer: &mut TcpStream,
-    tls_record_reader: &mut TLSRecordReader,
-    mut key_schedule: HandshakeKeySchedule,
-    client_cert: Vec<u8>,
-    signer: impl Fn(&[u8]) -> anyhow::Result<Vec<u8>>,
-) -> anyhow::Result<ApplicationKeySchedule> {
-    send_client_hello(tcp_writer, &mut key_schedule)?;
-    
-    let (next_tls_record, raw_vec) = tls_record_reader.read_tls_record_with_vec()?;
-    let server_hello = expect_server_hello(&next_tls_record)?;
-    debug!("server_hello: {:?}", server_hello);
-    key_schedule.add_transcript(&raw_vec);
+/// Our TPM-backed identity: the enrolled certificate followed by the intermediates in
+/// `SEC_POC_CLIENT_CHAIN`, if set. It is the default, so it is sent even to a server whose
+/// CertificateRequest names other CAs, unless `SEC_POC_NO_DEFAULT_IDENTITY` is set.
+fn client_identities<S>(client_cert: Vec<u8>, signer: S) -> anyhow::Result<IdentityStore<S>> {
+    let mut chain = vec![CertificateEntry::new(client_cert)];
+    if let Ok(path) = std::env::var("SEC_POC_CLIENT_CHAIN") {
+        chain.extend(client_identity::load_chain(Path::new(&path))?);
+    }
+    let identity = ClientIdentity::new(chain, cert_verify::TPM_SIGNATURE_SCHEMES.to_vec())?;
+    let mut identities = IdentityStore::new();
+    identities.add(identity, signer);
+    if std::env::var_os("SEC_POC_NO_DEFAULT_IDENTITY").is_some() {
+        identities.set_default(None)?;
+    }
+    Ok(identities)
+}
 
-    expect_key_share(&mut key_schedule, server_hello)?;
+/// The same two connections as `main`, made with [`AsyncTlsStream`] on a tokio runtime.
+async fn run_async_client(
+    config: Arc<ClientConfig>,
+    identities: Arc<IdentityStore<tpm::TpmSigningThread>>,
+) -> anyhow::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    for _ in 0..2 {
+        let mut tls_stream = AsyncTlsStream::connect_with_early_data(
+            config.clone(),
+            CLIENT_MESSAGE,
+            identities.clone(),
+            SystemRandom::new(),
+        )
+        .await?;
+        info!(
+            "negotiated application protocol: {:?}, resumed: {}, early data: {:?}",
+            tls_stream.alpn_protocol().map(String::from_utf8_lossy),
+            tls_stream.resumed(),
+            tls_stream.early_data_status()
+        );
 
-    let next_tls_record = tls_record_reader.read_tls_record()?;
-    if next_tls_record.hdr.record_type != TlsRecordType::ChangeCipherSpec {
-        anyhow::bail!("expected ChangeCipherSpec and more data");
+        let mut reader = tokio::io::BufReader::new(&mut tls_stream);
+        let mut next_blob = Vec::new();
+        reader.read_until(0, &mut next_blob).await?;
+        let app_string = std::str::from_utf8(&next_blob)?;
+        info!("app_blob: {:02X?}, app_string: {}", next_blob, app_string);
+        if tls_stream.early_data_status() != EarlyDataStatus::Accepted {
+            tls_stream.write_all(CLIENT_MESSAGE).await?;
+        }
+        tls_stream.shutdown().await?;
     }
+    Ok(())
+}
 
-    let blob = read_tls_encrypted(tls_record_reader, &mut key_schedule)?;
-    let p = parse_tls_extensions(&blob)?;
-    let (cert_requested, p) = process_server_cert(p)?;
-    process_finished(p, &mut key_schedule, &blob)?;
+/// Connection state that post-handshake messages use or change.
+struct PostHandshakeState {
+    server_name: String,
+    alpn_protocol: Option<Vec<u8>>,
+    /// Where tickets from NewSessionTicket go, for the next connection to the server.
+    session_cache: SessionCache,
+    /// We asked the server to update its keys and have not seen its KeyUpdate yet.
+    key_update_requested: bool,
+    /// Set once we have sent close_notify or a fatal alert; nothing more may be written.
+    write_closed: bool,
+}
 
-    if cert_requested {
-        send_client_cert(tcp_writer, &mut key_schedule, &client_cert)?;
-        send_cert_verify(tcp_writer, &mut key_schedule, signer)?;
+/// What became of the 0-RTT data passed to the handshake. Rejected data was never delivered
+/// and can be sent again as ordinary application data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EarlyDataStatus {
+    NotSent,
+    Accepted,
+    Rejected,
+}
+
+fn process_server_hello(
+    key_schedule: &mut HandshakeKeySchedule,
+    config: &ClientConfig,
+    hello_random: &HelloRandom,
+    raw_server_hello: &[u8],
+) -> anyhow::Result<()> {
+    let server_hello = expect_server_hello(raw_server_hello)?;
+    debug!("server_hello: {:?}", server_hello);
+    if server_hello.session_id.unwrap_or_default() != hello_random.session_id() {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            "ServerHello did not echo legacy_session_id",
+        ));
+    }
+    let cipher_suite = cipher_suite::negotiate(server_hello.cipher.0)?;
+    info!("negotiated cipher suite: {:?}", cipher_suite);
+    key_schedule.select_cipher_suite(cipher_suite)?;
+    key_schedule.add_transcript(raw_server_hello);
+
+    let extensions = codec::server_hello_extensions(raw_server_hello)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    if resumption::check_selected_identity(&extensions)? {
+        key_schedule.accept_psk()?;
+        info!("server accepted our session ticket");
     }
+    match codec::find_extension(&extensions, TlsExtensionType::KeyShare) {
+        Some(key_share) => expect_key_share(key_schedule, key_share),
+        None if key_schedule.psk_accepted()
+            && config.psk_key_exchange_modes.contains(&resumption::PSK_KE) =>
+        {
+            key_schedule.update_handshake_secret_psk_only()
+        }
+        None => Err(alert::fatal(
+            TlsAlertDescription::MissingExtension,
+            "ServerHello has no key_share",
+        )),
+    }
+}
 
-    let key_schedule = send_client_finished(tcp_writer, key_schedule)?;
-    Ok(key_schedule)
+fn process_hello_retry_request(
+    out: &mut Vec<u8>,
+    key_schedule: &mut HandshakeKeySchedule,
+    config: &ClientConfig,
+    hello_random: &HelloRandom,
+    ticket: Option<&SessionTicket>,
+    raw_hello_retry_request: &[u8],
+    rng: &dyn SecureRandom,
+) -> anyhow::Result<()> {
+    let hello_retry_request = hello_retry::parse_hello_retry_request(raw_hello_retry_request)?;
+    info!("hello_retry_request: {:?}", hello_retry_request);
+    key_schedule.select_cipher_suite(cipher_suite::negotiate(hello_retry_request.cipher_suite)?)?;
+    key_schedule.on_hello_retry_request(
+        raw_hello_retry_request,
+        hello_retry_request.selected_group,
+        &config.groups,
+        rng,
+    )?;
+    send_client_hello(
+        out,
+        key_schedule,
+        config,
+        hello_random,
+        ticket,
+        false,
+        hello_retry_request.cookie,
+    )
+}
 
+fn process_post_handshake_message(
+    out: &mut Vec<u8>,
+    key_schedule: &mut ApplicationKeySchedule,
+    raw: &[u8],
+    state: &mut PostHandshakeState,
+) -> anyhow::Result<()> {
+    match raw[0] {
+        NEW_SESSION_TICKET => {
+            let ticket = resumption::process_new_session_ticket(
+                raw,
+                key_schedule,
+                state.alpn_protocol.as_deref(),
+            )?;
+            if let Some(ticket) = ticket {
+                state.session_cache.insert(&state.server_name, ticket);
+            }
+            Ok(())
+        }
+        KEY_UPDATE => {
+            let update_requested = match raw {
+                [_, 0, 0, 1, request @ (UPDATE_NOT_REQUESTED | UPDATE_REQUESTED)] => {
+                    *request == UPDATE_REQUESTED
+                }
+                [_, 0, 0, 1, _] => {
+                    return Err(alert::fatal(
+                        TlsAlertDescription::IllegalParameter,
+                        "invalid KeyUpdate request_update",
+                    ));
+                }
+                _ => {
+                    return Err(alert::fatal(
+                        TlsAlertDescription::DecodeError,
+                        "malformed KeyUpdate",
+                    ));
+                }
+            };
+            info!("received KeyUpdate, update_requested: {}", update_requested);
+            key_schedule.update_read_secret()?;
+            // Once our side is closed we cannot answer, so only the server's keys move on.
+            if update_requested && !state.write_closed {
+                send_key_update(out, key_schedule, false)?;
+            }
+            state.key_update_requested = false;
+            Ok(())
+        }
+        msg_type => Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            format!("unexpected post-handshake message type {}", msg_type),
+        )),
+    }
 }
-fn process_finished(p: &[u8], key_schedule: &mut HandshakeKeySchedule, blob: &[u8]) -> anyhow::Result<()> {
-    let (p, finished) = parse_tls_message_handshake(p)
-        .map_err(|e| anyhow::anyhow!("parse_tls_message_handshake failed: {:?}", e))?;
 
+/// Parses a single complete handshake message.
+fn parse_handshake_message(raw: &[u8]) -> anyhow::Result<TlsMessage<'_>> {
+    let (rest, msg) = parse_tls_message_handshake(raw).map_err(|e| {
+        alert::fatal(
+            TlsAlertDescription::DecodeError,
+            format!("parse_tls_message_handshake failed: {:?}", e),
+        )
+    })?;
+    if !rest.is_empty() {
+        return Err(alert::fatal(
+            TlsAlertDescription::DecodeError,
+            "trailing data after handshake message",
+        ));
+    }
+    Ok(msg)
+}
 
+fn process_finished(raw: &[u8], key_schedule: &mut HandshakeKeySchedule) -> anyhow::Result<()> {
+    let finished = parse_handshake_message(raw)?;
     info!("finished: {:?}", finished);
-    if let TlsMessage::Handshake(tls_parser::TlsMessageHandshake::Finished(finished)) = finished {
-        key_schedule.add_transcript(&blob[..blob.len() - 17]);
-        key_schedule.on_server_finished()?;
+    if let TlsMessage::Handshake(TlsMessageHandshake::Finished(verify_data)) = finished {
+        let expected_verify_data = key_schedule.get_verify_server_data()?;
+        ring::constant_time::verify_slices_are_equal(&expected_verify_data, verify_data)
+            .map_err(|_| {
+                alert::fatal(
+                    TlsAlertDescription::DecryptError,
+                    "server Finished verify_data mismatch",
+                )
+            })?;
+        info!("server Finished verified");
+        key_schedule.add_transcript(raw);
+        key_schedule.on_server_finished()
     } else {
-        anyhow::bail!("expected Finished");
+        Err(alert::fatal(TlsAlertDescription::UnexpectedMessage, "expected Finished"))
     }
-
-    let (p, aead_tag) = take(16usize + 1usize)(p)
-        .map_err(|e: nom::Err<nom::error::Error<_>>| anyhow::anyhow!("take failed: {:?}", e))?;
-
-    info!("Application finished p = {:02X?}\n\n\n\n\n Writing Client Handshake Finish", p);
-    if p.is_empty() { Ok(()) } else { Err(anyhow::anyhow!("expected empty")) }
-}
-fn parse_tls_extensions(blob: &[u8]) -> anyhow::Result<&[u8]> {
-    let (p, tls_message_exts) = parse_tls_message_handshake(&blob)
-        .map_err(|e| anyhow::anyhow!("parse_tls_extensions failed: {:?}", e))?;
-    // parse server cert
-    info!("exts: {:?}", tls_message_exts);
-    Ok(p)
 }
 
-fn process_server_cert(p: &[u8]) -> anyhow::Result<(bool, &[u8])> {
-    let mut cert_requested = false;
-    let (p, cert_req) = parse_tls_message_handshake(p)
-        .map_err(|e| anyhow::anyhow!("parse_tls_message_handshake failed: {:?}", e))?;
-    if let TlsMessage::Handshake(tls_parser::TlsMessageHandshake::CertificateRequest(_)) = cert_req
+/// A server accepting 0-RTT data must have resumed with the ticket's cipher suite and ALPN
+/// protocol (RFC 8446, section 4.2.10).
+fn check_early_data_accepted(
+    key_schedule: &HandshakeKeySchedule,
+    encrypted_extensions: &EncryptedExtensions,
+    ticket: &SessionTicket,
+) -> anyhow::Result<()> {
+    if !key_schedule.psk_accepted()
+        || key_schedule.cipher_suite.id != ticket.cipher_suite.id
+        || encrypted_extensions.alpn_protocol != ticket.alpn_protocol
     {
-        cert_requested = true;
-        info!("cert_req: {:?}", cert_req);
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            "server accepted early data for a different session",
+        ));
     }
+    Ok(())
+}
+
+fn parse_tls_extensions(
+    raw: &[u8],
+    key_schedule: &mut HandshakeKeySchedule,
+    config: &ClientConfig,
+    early_data_offered: bool,
+) -> anyhow::Result<EncryptedExtensions> {
+    let encrypted_extensions = encrypted_extensions::parse_encrypted_extensions(
+        raw,
+        &config.alpn_protocols,
+        early_data_offered,
+    )?;
+    info!("encrypted_extensions: {:?}", encrypted_extensions);
+    key_schedule.add_transcript(raw);
+    Ok(encrypted_extensions)
+}
 
-    let (p, server_cert) = if cert_requested {
-        parse_tls_message_handshake(p)
-            .map_err(|e| anyhow::anyhow!("parse_tls_message_handshake failed: {:?}", e))?
+/// Verifies the server Certificate message and returns the leaf certificate.
+fn process_server_cert(
+    raw: &[u8],
+    key_schedule: &mut HandshakeKeySchedule,
+    server_name: &str,
+    trust_store: &TrustStore,
+) -> anyhow::Result<Vec<u8>> {
+    let server_cert = parse_handshake_message(raw)?;
+    info!("server_cert: {:?}", server_cert);
+    let leaf_cert = if let TlsMessage::Handshake(TlsMessageHandshake::Certificate(cert)) = &server_cert
+    {
+        let chain = cert.cert_chain.iter().map(|cert| cert.data).collect::<Vec<_>>();
+        let leaf_cert = chain.first().copied().ok_or(alert::fatal(
+            TlsAlertDescription::DecodeError,
+            "empty server certificate chain",
+        ))?;
+        trust_store
+            .verify_server_chain(&chain, server_name)
+            .map_err(trust_store::into_alert)?;
+        info!("server certificate chain verified for {}", server_name);
+        leaf_cert.to_vec()
     } else {
-        (p, cert_req)
+        return Err(alert::fatal(TlsAlertDescription::UnexpectedMessage, "expected Certificate"));
     };
-    info!("server_cert: {:?}", server_cert);
-    if let TlsMessage::Handshake(tls_parser::TlsMessageHandshake::Certificate(cert)) = server_cert {
-        cert.cert_chain.iter().for_each(|cert| {
-            x509_cert::certificate::Certificate::from_der(cert.data).unwrap();
-        });
-    }
-    let (p, cert_verify) = parse_tls_message_handshake(p)
-        .map_err(|e| anyhow::anyhow!("parse_tls_message_handshake failed: {:?}", e))?;
-    info!("cert_verify: {:?}", cert_verify);
-    Ok((cert_requested, p))
+    key_schedule.add_transcript(raw);
+    Ok(leaf_cert)
 }
 
-fn expect_key_share(key_schedule: &mut HandshakeKeySchedule, server_hello: &TlsServerHelloContents) -> anyhow::Result<()> {
-    let first_ext = server_hello
-        .ext
-        .first()
-        .ok_or(anyhow::anyhow!("no extensions"))?;
-    if let TlsExtension::KeyShare(KeyShareServerHello { server_share }) = first_ext {
-        key_schedule.update_handshake_secret(server_share.kx)?;
+fn process_server_cert_verify(
+    raw: &[u8],
+    key_schedule: &mut HandshakeKeySchedule,
+    leaf_cert: &[u8],
+) -> anyhow::Result<()> {
+    let server_cert_verify = parse_handshake_message(raw)?;
+    info!("cert_verify: {:?}", server_cert_verify);
+    if let TlsMessage::Handshake(TlsMessageHandshake::CertificateVerify(server_cert_verify)) =
+        server_cert_verify
+    {
+        let transcript_hash = key_schedule.transcript_hash_context.clone().finish();
+        cert_verify::verify_server_signature(
+            leaf_cert,
+            server_cert_verify.scheme,
+            server_cert_verify.signature,
+            transcript_hash.as_ref(),
+        )?;
+    } else {
+        return Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            "expected CertificateVerify",
+        ));
     }
+    key_schedule.add_transcript(raw);
     Ok(())
 }
 
-fn send_client_hello(tcp_writer: &mut TcpStream, key_schedule: &mut HandshakeKeySchedule) -> anyhow::Result<()> {
-    let kx = key_schedule.get_client_public_key();
-    let client_hello = gen_client_hello(&kx);
+/// Reads the server's KeyShareEntry, which must be for a group we sent a share for.
+fn expect_key_share(
+    key_schedule: &mut HandshakeKeySchedule,
+    key_share: &[u8],
+) -> anyhow::Result<()> {
+    let (group, server_share) = match key_share {
+        [hi, lo, len_hi, len_lo, kx @ ..]
+            if u16::from_be_bytes([*len_hi, *len_lo]) as usize == kx.len() =>
+        {
+            (NamedGroup(u16::from_be_bytes([*hi, *lo])), kx)
+        }
+        _ => {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                "malformed ServerHello key_share",
+            ));
+        }
+    };
+    key_schedule.update_handshake_secret(group, server_share)
+}
+
+fn send_client_hello(
+    out: &mut Vec<u8>,
+    key_schedule: &mut HandshakeKeySchedule,
+    config: &ClientConfig,
+    hello_random: &HelloRandom,
+    ticket: Option<&SessionTicket>,
+    offer_early_data: bool,
+    cookie: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let key_shares = key_schedule.client_key_shares();
+    let ticket = ticket.filter(|_| key_schedule.can_offer_psk());
+    let offered_psks = ticket.map(resumption::offered_psks);
+    let client_hello = gen_client_hello(
+        config,
+        hello_random,
+        &key_shares,
+        cookie,
+        offered_psks.as_deref(),
+        offer_early_data,
+    );
     {
-        let buf = client_hello.serialize()?;
-        key_schedule.add_transcript(&buf[5..]);
+        let mut buf = client_hello.serialize()?;
+        if let Some(ticket) = ticket {
+            // pre_shared_key is the last extension, so the binders end the message.
+            let binders_start = buf.len() - resumption::binders_len(ticket);
+            let binder = key_schedule.psk_binder(&buf[5..binders_start])?;
+            buf[binders_start + 3..].copy_from_slice(&binder);
+        }
+        key_schedule.add_client_hello(&buf[5..]);
         debug!(
             "client_hello: {:?}, buf({}): {:02X?}",
             client_hello,
             buf.len(),
             buf
         );
-        tcp_writer.write_all(&buf)?;
+        out.extend_from_slice(&buf);
     };
     Ok(())
 }
 
-fn send_client_finished(tcp_writer: &mut TcpStream, mut key_schedule: HandshakeKeySchedule) -> anyhow::Result<ApplicationKeySchedule> {
+fn send_client_finished(
+    out: &mut Vec<u8>,
+    key_schedule: &mut HandshakeKeySchedule,
+) -> anyhow::Result<()> {
     let verify_data = key_schedule.get_verify_client_data()?;
     let client_handshake_finished = Finished(&verify_data);
-    send_handshake_tls_message(tcp_writer, &mut key_schedule, client_handshake_finished)?;
-
-    let mut key_schedule = key_schedule.into_application_key_schedule()?;
-    key_schedule.add_transcript(&verify_data);
-    Ok(key_schedule)
+    send_handshake_tls_message(out, key_schedule, client_handshake_finished)
 }
 
-fn read_tls_encrypted<T: TlsEncryptDecrypt>(
-    tls_record_reader: &mut TLSRecordReader,
+/// Decrypts a protected record, returning its inner content type and its content without
+/// padding.
+fn decrypt_record<T: TlsEncryptDecrypt>(
     key_schedule: &mut T,
-) -> anyhow::Result<Vec<u8>> {
-    let (next_tls_record, hdr_buf) = tls_record_reader.read_tls_encrypted_record()?;
-    let mut blob = Vec::from(next_tls_record.msg.blob);
-    key_schedule.decrypt_tls_encrypted(hdr_buf, &mut blob)?;
-    info!("application_data: {:02X?}...", &blob[0..10]);
-    Ok(blob)
+    hdr: &TlsRecordHeader,
+    hdr_buf: [u8; 5],
+    mut blob: Vec<u8>,
+) -> anyhow::Result<(TlsRecordType, Vec<u8>)> {
+    if hdr.record_type != TlsRecordType::ApplicationData {
+        return Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            format!("unprotected {:?} record", hdr.record_type),
+        ));
+    }
+    let plaintext = key_schedule.decrypt_tls_encrypted(hdr_buf, &mut blob)?;
+    let (content_type, content) = record::parse_inner_plaintext(plaintext)?;
+    info!(
+        "{:?}: {:02X?}...",
+        content_type,
+        &content[..content.len().min(10)]
+    );
+    Ok((content_type, content.to_vec()))
 }
 
 fn init_logger() {
@@ -267,60 +650,113 @@ fn init_logger() {
         .init();
 }
 
-fn expect_server_hello<'a>(
-    tls_record: &'a TlsPlaintext,
-) -> anyhow::Result<&'a TlsServerHelloContents<'a>> {
-    if tls_record.hdr.record_type == TlsRecordType::Handshake {
-        let handshake = tls_record
-            .msg
-            .first()
-            .ok_or(anyhow::anyhow!("no handshake"))?;
-        if let TlsMessage::Handshake(tls_parser::TlsMessageHandshake::ServerHello(sh)) = handshake {
-                return Ok(sh);
-            }
-        }
-    anyhow::bail!("expected ServerHello");
+fn expect_server_hello(raw_server_hello: &[u8]) -> anyhow::Result<TlsServerHelloContents<'_>> {
+    let handshake = parse_handshake_message(raw_server_hello)?;
+    if let TlsMessage::Handshake(tls_parser::TlsMessageHandshake::ServerHello(sh)) = handshake {
+        return Ok(sh);
+    }
+    Err(alert::fatal(TlsAlertDescription::UnexpectedMessage, "expected ServerHello"))
+}
+
+/// ClientHello random and legacy_session_id, fixed for the whole handshake so that the
+/// ClientHello sent after a HelloRetryRequest repeats them.
+struct HelloRandom {
+    random: [u8; 32],
+    session_id: Option<[u8; 32]>,
+}
+
+impl HelloRandom {
+    fn generate(
+        rng: &dyn SecureRandom,
+        middlebox_compatibility_mode: bool,
+    ) -> anyhow::Result<Self> {
+        let mut random = [0u8; 32];
+        rng.fill(&mut random)
+            .map_err(|e| anyhow::anyhow!("SecureRandom::fill failed: {:?}", e))?;
+        let session_id = if middlebox_compatibility_mode {
+            let mut session_id = [0u8; 32];
+            rng.fill(&mut session_id)
+                .map_err(|e| anyhow::anyhow!("SecureRandom::fill failed: {:?}", e))?;
+            Some(session_id)
+        } else {
+            None
+        };
+        Ok(Self { random, session_id })
+    }
+
+    fn session_id(&self) -> &[u8] {
+        self.session_id.as_ref().map_or(&[], |id| id.as_slice())
+    }
 }
 
-const RANDOM32: [u8; 32] = [
-    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26,
-    27, 28, 29, 30, 31, 32,
-];
-fn gen_client_hello(kx: &[u8]) -> TlsPlaintext {
+fn gen_client_hello<'a>(
+    config: &'a ClientConfig,
+    hello_random: &'a HelloRandom,
+    key_shares: &'a [(NamedGroup, Vec<u8>)],
+    cookie: Option<&'a [u8]>,
+    offered_psks: Option<&'a [u8]>,
+    offer_early_data: bool,
+) -> TlsPlaintext<'a> {
     let hdr = tls_parser::TlsRecordHeader {
         record_type: TlsRecordType::Handshake,
         version: tls_parser::TlsVersion::Tls10,
         len: 0,
     };
 
-    let named_group = NamedGroup::EcdhX25519;
     let supported_versions = TlsExtension::SupportedVersions(vec![tls_parser::TlsVersion::Tls13]);
     let signature_algorithms =
-        TlsExtension::SignatureAlgorithms(vec![SignatureScheme::rsa_pss_rsae_sha256]);
-    let elliptic_curves = TlsExtension::EllipticCurves(vec![named_group]);
+        TlsExtension::SignatureAlgorithms(cert_verify::SUPPORTED_SIGNATURE_SCHEMES.to_vec());
+    let elliptic_curves = TlsExtension::EllipticCurves(config.groups.clone());
     let key_share = TlsExtension::KeyShare(KeyShareClientHello {
-        client_shares: vec![KeyShareEntry {
-            group: named_group,
-            kx,
-        }],
+        client_shares: key_shares
+            .iter()
+            .map(|(group, kx)| KeyShareEntry { group: *group, kx })
+            .collect(),
     });
-    let ext = vec![
+    let mut ext = Vec::new();
+    if let Some(host_name) = config.sni_host_name() {
+        ext.push(TlsExtension::SNI(vec![(
+            tls_parser::SNIType::HostName,
+            host_name.as_bytes(),
+        )]));
+    }
+    ext.extend([
         elliptic_curves,
         signature_algorithms,
         // ec_point_formats,
         supported_versions,
         key_share,
-    ];
+    ]);
+    if !config.alpn_protocols.is_empty() {
+        ext.push(TlsExtension::ALPN(
+            config.alpn_protocols.iter().map(|p| p.as_slice()).collect(),
+        ));
+    }
+    if let Some(cookie) = cookie {
+        ext.push(TlsExtension::Cookie(cookie));
+    }
+    if config.post_handshake_auth {
+        ext.push(TlsExtension::PostHandshakeAuth);
+    }
+    if let Some(offered_psks) = offered_psks {
+        ext.push(TlsExtension::PskExchangeModes(
+            config.psk_key_exchange_modes.clone(),
+        ));
+        if offer_early_data {
+            ext.push(TlsExtension::EarlyData(None));
+        }
+        // Must be the last extension (RFC 8446, section 4.2.11).
+        ext.push(TlsExtension::PreSharedKey(offered_psks));
+    }
 
     let client_hello_contents = tls_parser::TlsClientHelloContents {
         version: tls_parser::TlsVersion::Tls12,
-        random: &RANDOM32,
-        session_id: None,
-        ciphers: vec![
-            tls_parser::TlsCipherSuiteID(TLS_AES_128_GCM_SHA256),
-            // tls_parser::TlsCipherSuiteID(TLS_AES_256_GCM_SHA384),
-            // tls_parser::TlsCipherSuiteID(TLS_CHACHA20_POLY1305_SHA256),
-        ],
+        random: &hello_random.random,
+        session_id: hello_random.session_id.as_ref().map(|id| id.as_slice()),
+        ciphers: cipher_suite::preferred_cipher_suites()
+            .iter()
+            .map(|suite| tls_parser::TlsCipherSuiteID(suite.id))
+            .collect(),
         comp: vec![tls_parser::TlsCompressionID(0)],
         ext,
     };
@@ -332,26 +768,131 @@ fn gen_client_hello(kx: &[u8]) -> TlsPlaintext {
         msg: vec![client_hello_handshake],
     }
 }
+/// Sends our Certificate in answer to `request`. An empty `chain` tells the server we have
+/// no suitable certificate.
 fn send_client_cert(
-    tcp_writer: &mut TcpStream,
+    out: &mut Vec<u8>,
     key_schedule: &mut HandshakeKeySchedule,
-    client_cert: &[u8],
+    request: &CertificateRequest,
+    chain: &[CertificateEntry],
 ) -> anyhow::Result<()> {
-    info!("Sending client cert");
-    let client_req_tls_message = TlsMessageHandshake::Certificate(TlsCertificateContents {
-        cert_chain: vec![RawCertificate { data: &client_cert }],
-    });
-    send_handshake_tls_message(tcp_writer, key_schedule, client_req_tls_message)
+    info!("Sending client cert chain of {} certificates", chain.len());
+    let certificate = client_identity::encode_certificate(request, chain)?;
+    send_handshake_message(out, key_schedule, &certificate)
 }
 
 fn send_handshake_tls_message(
-    tcp_writer: &mut TcpStream,
+    out: &mut Vec<u8>,
     key_schedule: &mut HandshakeKeySchedule,
     tls_message: TlsMessageHandshake,
 ) -> anyhow::Result<()> {
-    let tls_message_buf = tls_message.serialize()?;
-    let mut tls_encrypted_message_buf = tls_message_buf.clone();
-    tls_encrypted_message_buf.push(u8::from(TlsRecordType::Handshake));
+    send_handshake_message(out, key_schedule, &tls_message.serialize()?)
+}
+
+fn send_handshake_message(
+    out: &mut Vec<u8>,
+    key_schedule: &mut HandshakeKeySchedule,
+    msg: &[u8],
+) -> anyhow::Result<()> {
+    send_records(out, key_schedule, TlsRecordType::Handshake, msg)?;
+    key_schedule.add_transcript(msg);
+    Ok(())
+}
+
+/// Dummy ChangeCipherSpec sent before the encrypted client flight in middlebox
+/// compatibility mode (RFC 8446, Appendix D.4).
+fn send_change_cipher_spec(out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let hdr = TlsRecordHeader {
+        record_type: TlsRecordType::ChangeCipherSpec,
+        version: tls_parser::TlsVersion::Tls12,
+        len: 1,
+    };
+    let mut buf = hdr.serialize()?;
+    buf.push(1);
+    out.extend_from_slice(&buf);
+    Ok(())
+}
+
+/// Sends a KeyUpdate and switches to the next client traffic secret. With `update_requested`
+/// the server has to update its keys as well.
+fn send_key_update(
+    out: &mut Vec<u8>,
+    key_schedule: &mut ApplicationKeySchedule,
+    update_requested: bool,
+) -> anyhow::Result<()> {
+    let request = if update_requested { UPDATE_REQUESTED } else { UPDATE_NOT_REQUESTED };
+    info!("sending KeyUpdate, update_requested: {}", update_requested);
+    let key_update = [KEY_UPDATE, 0, 0, 1, request];
+    send_encrypted_record(out, key_schedule, TlsRecordType::Handshake, &key_update)?;
+    key_schedule.update_write_secret()
+}
+
+/// Tells the server we will send no more data on this connection.
+fn send_close_notify<T: TlsEncrypt>(
+    out: &mut Vec<u8>,
+    key_schedule: &mut T,
+) -> anyhow::Result<()> {
+    info!("sending close_notify");
+    send_encrypted_record(out, key_schedule, TlsRecordType::Alert, &alert::CLOSE_NOTIFY)
+}
+
+fn send_plaintext_alert(out: &mut Vec<u8>, err: &anyhow::Error) -> anyhow::Result<()> {
+    if let Some(description) = alert::alert_for(err) {
+        info!("sending plaintext fatal alert {:?} for: {:?}", description, err);
+        let hdr = TlsRecordHeader {
+            record_type: TlsRecordType::Alert,
+            version: tls_parser::TlsVersion::Tls12,
+            len: 2,
+        };
+        let mut buf = hdr.serialize()?;
+        buf.extend_from_slice(&alert::alert_payload(description));
+        out.extend_from_slice(&buf);
+    }
+    Ok(())
+}
+
+fn send_fatal_alert<T: TlsEncrypt>(
+    out: &mut Vec<u8>,
+    key_schedule: &mut T,
+    err: &anyhow::Error,
+) -> anyhow::Result<()> {
+    if let Some(description) = alert::alert_for(err) {
+        info!("sending fatal alert {:?} for: {:?}", description, err);
+        let payload = alert::alert_payload(description);
+        send_encrypted_record(out, key_schedule, TlsRecordType::Alert, &payload)?;
+    }
+    Ok(())
+}
+
+/// Encrypts and sends `data` as application data.
+fn send_application_data<T: TlsEncrypt>(
+    out: &mut Vec<u8>,
+    key_schedule: &mut T,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    send_records(out, key_schedule, TlsRecordType::ApplicationData, data)
+}
+
+/// Sends `data` in as many records of at most [`record::MAX_FRAGMENT_LEN`] bytes as needed.
+fn send_records<T: TlsEncrypt>(
+    out: &mut Vec<u8>,
+    key_schedule: &mut T,
+    record_type: TlsRecordType,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    for chunk in data.chunks(record::MAX_FRAGMENT_LEN) {
+        send_encrypted_record(out, key_schedule, record_type, chunk)?;
+    }
+    Ok(())
+}
+
+fn send_encrypted_record<T: TlsEncrypt>(
+    out: &mut Vec<u8>,
+    key_schedule: &mut T,
+    record_type: TlsRecordType,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut tls_encrypted_message_buf = record::encode_inner_plaintext(record_type, payload)?;
     let wrapped_hdr = tls_parser::TlsRecordHeader {
         record_type: TlsRecordType::ApplicationData,
         version: tls_parser::TlsVersion::Tls12,
@@ -368,38 +909,91 @@ fn send_handshake_tls_message(
     let mut encrypted_buf = tls_encrypted.serialize()?;
     encrypted_buf.extend_from_slice(tag.as_ref());
     debug!("tag size = {:02X?}", tag.as_ref());
-    tcp_writer.write_all(&encrypted_buf)?;
-    key_schedule.add_transcript(&tls_message_buf);
+    out.extend_from_slice(&encrypted_buf);
     debug!(
         "sent({}) encrypted_buf ({}) [0..5] {:02X?}",
-        tls_message_buf.len(),
+        payload.len(),
         encrypted_buf.len(),
         &encrypted_buf[0..5]
     );
     Ok(())
 }
 
-fn send_cert_verify(
-    tcp_writer: &mut TcpStream,
-    key_schedule: &mut HandshakeKeySchedule,
-    signer: impl Fn(&[u8]) -> anyhow::Result<Vec<u8>>,
-) -> anyhow::Result<()> {
-    const CONTEXT_STRING: &[u8] = b"TLS 1.3, client CertificateVerify\0";
-    let signing_input = [
-        &[0x20; 64],
-        CONTEXT_STRING,
+/// The content the client CertificateVerify signs: the transcript hash through the client
+/// Certificate behind the TLS 1.3 context string (RFC 8446, section 4.4.3).
+fn cert_verify_signing_input(key_schedule: &HandshakeKeySchedule) -> Vec<u8> {
+    cert_verify::client_signing_input(
         key_schedule
             .transcript_hash_context
             .clone()
             .finish()
             .as_ref(),
-    ]
-    .concat();
-    let sig = signer(&signing_input)?;
+    )
+}
+
+fn send_cert_verify(
+    out: &mut Vec<u8>,
+    key_schedule: &mut HandshakeKeySchedule,
+    scheme: SignatureScheme,
+    sig: &[u8],
+) -> anyhow::Result<()> {
     let certificate_verify_content = tls_parser::CertificateVerifyContent {
-        scheme: SignatureScheme::rsa_pss_rsae_sha256,
-        signature: &sig,
+        scheme,
+        signature: sig,
     };
     let client_cert_verify = TlsMessageHandshake::CertificateVerify(certificate_verify_content);
-    send_handshake_tls_message(tcp_writer, key_schedule, client_cert_verify)
+    send_handshake_tls_message(out, key_schedule, client_cert_verify)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::application_key_schedule;
+
+    fn post_handshake_state(write_closed: bool) -> PostHandshakeState {
+        PostHandshakeState {
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            alpn_protocol: None,
+            session_cache: SessionCache::default(),
+            key_update_requested: false,
+            write_closed,
+        }
+    }
+
+    const KEY_UPDATE_REQUESTED: [u8; 5] = [KEY_UPDATE, 0, 0, 1, UPDATE_REQUESTED];
+
+    #[test]
+    fn requested_key_update_is_answered() {
+        let mut key_schedule = application_key_schedule(&[3; 32]);
+        let mut out = Vec::new();
+        let mut state = post_handshake_state(false);
+        process_post_handshake_message(
+            &mut out,
+            &mut key_schedule,
+            &KEY_UPDATE_REQUESTED,
+            &mut state,
+        )
+        .unwrap();
+        assert!(!out.is_empty());
+        assert_ne!(key_schedule.server_application_traffic_secret, [2; 32]);
+        assert_ne!(key_schedule.client_application_traffic_secret, [1; 32]);
+    }
+
+    #[test]
+    fn key_update_after_close_notify_only_updates_read_keys() {
+        let mut key_schedule = application_key_schedule(&[3; 32]);
+        let mut out = Vec::new();
+        let mut state = post_handshake_state(true);
+        process_post_handshake_message(
+            &mut out,
+            &mut key_schedule,
+            &KEY_UPDATE_REQUESTED,
+            &mut state,
+        )
+        .unwrap();
+        assert!(out.is_empty());
+        assert_ne!(key_schedule.server_application_traffic_secret, [2; 32]);
+        assert_eq!(key_schedule.client_application_traffic_secret, [1; 32]);
+        assert_eq!(key_schedule.write_seq_num, 0);
+    }
 }
@@ -0,0 +1,129 @@
+use crate::cert_verify;
+use crate::certificate_request::{self, CertificateRequest};
+use crate::client_identity::{self, ClientIdentity};
+use crate::enc_dec::{self, TlsEncrypt, TlsEncryptDecrypt};
+use crate::key_schedule::ApplicationKeySchedule;
+use log::info;
+use tls_parser::{Serialize, SignatureScheme, TlsMessageHandshake};
+
+/// The next part of our answer.
+#[derive(PartialEq)]
+enum Expect {
+    Certificate,
+    /// The CertificateVerify signature, made with `scheme`.
+    Signature {
+        scheme: SignatureScheme,
+    },
+    Finished,
+}
+
+/// Our answer to a CertificateRequest the server sent after the handshake (RFC 8446,
+/// section 4.6.2): Certificate, CertificateVerify if the certificate is not empty, and
+/// Finished. The answer is held back until it is complete, so its messages go out together.
+pub(crate) struct PostHandshakeAuth {
+    request: CertificateRequest,
+    /// The handshake transcript through our Finished, followed by the CertificateRequest and
+    /// the messages of our answer so far.
+    transcript: ring::digest::Context,
+    messages: Vec<u8>,
+    expect: Expect,
+}
+
+impl PostHandshakeAuth {
+    /// Starts the answer to `raw`, a CertificateRequest received under `key_schedule`.
+    pub(crate) fn new(raw: &[u8], key_schedule: &ApplicationKeySchedule) -> anyhow::Result<Self> {
+        let request = certificate_request::parse_certificate_request(raw)?;
+        info!(
+            "received post-handshake CertificateRequest with context {:02X?}",
+            request.context
+        );
+        let mut transcript = key_schedule.transcript_hash_context().clone();
+        transcript.update(raw);
+        Ok(Self {
+            request,
+            transcript,
+            messages: Vec::new(),
+            expect: Expect::Certificate,
+        })
+    }
+
+    pub(crate) fn request(&self) -> &CertificateRequest {
+        &self.request
+    }
+
+    /// Adds our Certificate: `identity`'s chain, or an empty one without an identity. With
+    /// an identity, returns the scheme negotiated for it and the content CertificateVerify
+    /// has to sign.
+    pub(crate) fn add_certificate(
+        &mut self,
+        identity: Option<&ClientIdentity>,
+    ) -> anyhow::Result<Option<(SignatureScheme, Vec<u8>)>> {
+        if self.expect != Expect::Certificate {
+            anyhow::bail!("unexpected client certificate");
+        }
+        let Some(identity) = identity else {
+            info!("no client certificate to send after the handshake");
+            self.add_message(&client_identity::encode_certificate(&self.request, &[])?);
+            self.expect = Expect::Finished;
+            return Ok(None);
+        };
+        let scheme = cert_verify::select_client_signature_scheme(
+            &self.request.signature_schemes,
+            identity.signature_schemes(),
+        )?;
+        info!(
+            "answering post-handshake CertificateRequest with a chain of {} certificates",
+            identity.chain().len()
+        );
+        self.add_message(&client_identity::encode_certificate(
+            &self.request,
+            identity.chain(),
+        )?);
+        self.expect = Expect::Signature { scheme };
+        let transcript_hash = self.transcript.clone().finish();
+        Ok(Some((
+            scheme,
+            cert_verify::client_signing_input(transcript_hash.as_ref()),
+        )))
+    }
+
+    /// Adds our CertificateVerify with the signature asked for by `add_certificate`.
+    pub(crate) fn add_certificate_verify(&mut self, signature: &[u8]) -> anyhow::Result<()> {
+        let Expect::Signature { scheme } = self.expect else {
+            anyhow::bail!("unexpected CertificateVerify signature");
+        };
+        let certificate_verify =
+            TlsMessageHandshake::CertificateVerify(tls_parser::CertificateVerifyContent {
+                scheme,
+                signature,
+            })
+            .serialize()?;
+        self.add_message(&certificate_verify);
+        self.expect = Expect::Finished;
+        Ok(())
+    }
+
+    /// Completes the answer with Finished, keyed with the current client application traffic
+    /// secret, and returns its messages.
+    pub(crate) fn finish(
+        mut self,
+        key_schedule: &ApplicationKeySchedule,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.expect != Expect::Finished {
+            anyhow::bail!("post-handshake authentication is not ready to finish");
+        }
+        let verify_data = enc_dec::verify_data(
+            key_schedule.cipher_suite(),
+            key_schedule.client_traffic_secret(),
+            self.transcript.finish().as_ref(),
+        )?;
+        let finished = TlsMessageHandshake::Finished(&verify_data).serialize()?;
+        self.messages.extend_from_slice(&finished);
+        Ok(self.messages)
+    }
+
+    fn add_message(&mut self, msg: &[u8]) {
+        self.transcript.update(msg);
+        self.messages.extend_from_slice(msg);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::alert;
+use tls_parser::{TlsAlertDescription, TlsRecordType};
+
+/// Largest TLSInnerPlaintext content, 2^14 bytes.
+pub(crate) const MAX_FRAGMENT_LEN: usize = 1 << 14;
+
+/// Splits a decrypted TLSInnerPlaintext into its real content type and content, dropping the
+/// zero padding (RFC 8446, section 5.4).
+pub(crate) fn parse_inner_plaintext(plaintext: &[u8]) -> anyhow::Result<(TlsRecordType, &[u8])> {
+    let Some(type_pos) = plaintext.iter().rposition(|&b| b != 0) else {
+        return Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            "TLSInnerPlaintext has no content type",
+        ));
+    };
+    let content_type = TlsRecordType(plaintext[type_pos]);
+    let content = &plaintext[..type_pos];
+    if plaintext.len() > MAX_FRAGMENT_LEN + 1 {
+        return Err(alert::fatal(
+            TlsAlertDescription::RecordOverflow,
+            format!("TLSInnerPlaintext too large: {} bytes", plaintext.len()),
+        ));
+    }
+    match content_type {
+        TlsRecordType::Handshake | TlsRecordType::Alert if content.is_empty() => {
+            Err(alert::fatal(
+                TlsAlertDescription::UnexpectedMessage,
+                format!("empty {:?} record", content_type),
+            ))
+        }
+        TlsRecordType::Handshake | TlsRecordType::Alert | TlsRecordType::ApplicationData => {
+            Ok((content_type, content))
+        }
+        _ => Err(alert::fatal(
+            TlsAlertDescription::UnexpectedMessage,
+            format!("unexpected inner content type {:?}", content_type),
+        )),
+    }
+}
+
+/// Builds the TLSInnerPlaintext for one record. We never pad.
+pub(crate) fn encode_inner_plaintext(
+    content_type: TlsRecordType,
+    content: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if content.len() > MAX_FRAGMENT_LEN {
+        anyhow::bail!("record content too large: {} bytes", content.len());
+    }
+    let mut plaintext = Vec::with_capacity(content.len() + 1);
+    plaintext.extend_from_slice(content);
+    plaintext.push(u8::from(content_type));
+    Ok(plaintext)
+}
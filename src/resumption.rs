@@ -0,0 +1,392 @@
+use crate::alert;
+use crate::cipher_suite::CipherSuite;
+use crate::codec;
+use crate::key_schedule::{ApplicationKeySchedule, HKDF, HkdfLabel};
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tls_parser::nom;
+use tls_parser::nom::multi::length_data;
+use tls_parser::nom::number::complete::{be_u8, be_u16, be_u32};
+use tls_parser::{TlsAlertDescription, TlsExtensionType};
+
+/// psk_key_exchange_modes values (RFC 8446, section 4.2.9).
+pub(crate) const PSK_KE: u8 = 0;
+pub(crate) const PSK_DHE_KE: u8 = 1;
+
+const NEW_SESSION_TICKET: u8 = 4;
+/// Servers must not issue tickets that live longer than seven days.
+const MAX_TICKET_LIFETIME: u32 = 604800;
+const MAX_TICKETS_PER_SERVER: usize = 4;
+
+/// A ticket from a NewSessionTicket together with the PSK derived for it.
+pub(crate) struct SessionTicket {
+    pub(crate) cipher_suite: &'static CipherSuite,
+    pub(crate) ticket: Vec<u8>,
+    pub(crate) psk: Vec<u8>,
+    /// Largest amount of 0-RTT data the server accepts with this ticket; 0 means none.
+    pub(crate) max_early_data_size: u32,
+    /// The ALPN protocol of the connection the ticket came from, which 0-RTT data must use.
+    pub(crate) alpn_protocol: Option<Vec<u8>>,
+    ticket_age_add: u32,
+    lifetime: Duration,
+    received_at: Instant,
+}
+
+impl SessionTicket {
+    fn is_expired(&self) -> bool {
+        self.received_at.elapsed() >= self.lifetime
+    }
+
+    /// The ticket age in milliseconds plus ticket_age_add, so that the age sent in the clear
+    /// cannot be used to link connections.
+    pub(crate) fn obfuscated_ticket_age(&self) -> u32 {
+        obfuscate_ticket_age(self.received_at.elapsed(), self.ticket_age_add)
+    }
+
+    /// Whether `len` bytes of 0-RTT data can be sent with this ticket. The server only
+    /// accepts early data under the ALPN protocol of the original connection.
+    pub(crate) fn allows_early_data(&self, len: usize, alpn_protocols: &[Vec<u8>]) -> bool {
+        len > 0
+            && len <= self.max_early_data_size as usize
+            && self
+                .alpn_protocol
+                .as_ref()
+                .is_none_or(|protocol| alpn_protocols.contains(protocol))
+    }
+}
+
+/// The ticket age in milliseconds plus `ticket_age_add`, modulo 2^32 (RFC 8446, section 4.2.11).
+fn obfuscate_ticket_age(age: Duration, ticket_age_add: u32) -> u32 {
+    (age.as_millis() as u32).wrapping_add(ticket_age_add)
+}
+
+/// Tickets received from each server. A ticket is handed out once and then forgotten, since
+/// reusing it would let an observer link the connections.
+#[derive(Clone, Default)]
+pub(crate) struct SessionCache {
+    tickets: Arc<Mutex<HashMap<String, VecDeque<SessionTicket>>>>,
+}
+
+impl SessionCache {
+    pub(crate) fn insert(&self, server_name: &str, ticket: SessionTicket) {
+        let mut tickets = self.tickets.lock().unwrap();
+        let server_tickets = tickets.entry(server_name.to_string()).or_default();
+        if server_tickets.len() == MAX_TICKETS_PER_SERVER {
+            server_tickets.pop_front();
+        }
+        server_tickets.push_back(ticket);
+    }
+
+    /// Takes the newest ticket for `server_name` that has not expired.
+    pub(crate) fn take(&self, server_name: &str) -> Option<SessionTicket> {
+        let mut tickets = self.tickets.lock().unwrap();
+        let server_tickets = tickets.get_mut(server_name)?;
+        server_tickets.retain(|ticket| !ticket.is_expired());
+        server_tickets.pop_back()
+    }
+}
+
+/// ticket_lifetime, ticket_age_add, ticket_nonce and ticket of a NewSessionTicket.
+type TicketFields<'a> = (u32, u32, &'a [u8], &'a [u8]);
+
+fn parse_fields(i: &[u8]) -> nom::IResult<&[u8], TicketFields<'_>> {
+    let (i, lifetime) = be_u32(i)?;
+    let (i, ticket_age_add) = be_u32(i)?;
+    let (i, nonce) = length_data(be_u8)(i)?;
+    let (i, ticket) = length_data(be_u16)(i)?;
+    Ok((i, (lifetime, ticket_age_add, nonce, ticket)))
+}
+
+/// Parses a NewSessionTicket and derives its PSK from the resumption_master_secret. Returns
+/// `None` for a ticket the server says must not be used.
+pub(crate) fn process_new_session_ticket(
+    msg: &[u8],
+    key_schedule: &ApplicationKeySchedule,
+    alpn_protocol: Option<&[u8]>,
+) -> anyhow::Result<Option<SessionTicket>> {
+    let (msg_type, body) = codec::split_handshake(msg)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    if msg_type != NEW_SESSION_TICKET {
+        anyhow::bail!("expected NewSessionTicket, got handshake type {}", msg_type);
+    }
+    let (i, (lifetime, ticket_age_add, nonce, ticket)) = parse_fields(body)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, format!("{:?}", e)))?;
+    let extensions = codec::parse_extensions(i)
+        .map_err(|e| alert::fatal(TlsAlertDescription::DecodeError, e))?;
+    let early_data = codec::find_extension(&extensions, TlsExtensionType::EarlyData);
+    let max_early_data_size = match early_data {
+        Some(&[a, b, c, d]) => u32::from_be_bytes([a, b, c, d]),
+        Some(_) => {
+            return Err(alert::fatal(
+                TlsAlertDescription::DecodeError,
+                "malformed NewSessionTicket early_data",
+            ));
+        }
+        None => 0,
+    };
+    if ticket.is_empty() {
+        return Err(alert::fatal(TlsAlertDescription::DecodeError, "empty session ticket"));
+    }
+    if lifetime > MAX_TICKET_LIFETIME {
+        return Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            format!("ticket lifetime {} exceeds seven days", lifetime),
+        ));
+    }
+    if lifetime == 0 {
+        info!("discarding NewSessionTicket with zero lifetime");
+        return Ok(None);
+    }
+    let cipher_suite = key_schedule.cipher_suite;
+    let psk = HKDF::new(cipher_suite, &key_schedule.resumption_master_secret).expand_label(
+        &HkdfLabel::new(cipher_suite.hash_len() as u16, "resumption", nonce),
+    )?;
+    info!(
+        "received session ticket, lifetime {}s, max_early_data_size {}",
+        lifetime, max_early_data_size
+    );
+    Ok(Some(SessionTicket {
+        cipher_suite,
+        ticket: ticket.to_vec(),
+        psk,
+        max_early_data_size,
+        alpn_protocol: alpn_protocol.map(|p| p.to_vec()),
+        ticket_age_add,
+        lifetime: Duration::from_secs(lifetime.into()),
+        received_at: Instant::now(),
+    }))
+}
+
+/// Length of the binders list at the end of the pre_shared_key extension.
+pub(crate) fn binders_len(ticket: &SessionTicket) -> usize {
+    2 + 1 + ticket.cipher_suite.hash_len()
+}
+
+/// Body of the ClientHello pre_shared_key extension offering `ticket`, with a zero binder
+/// to be filled in once the rest of the ClientHello is known.
+pub(crate) fn offered_psks(ticket: &SessionTicket) -> Vec<u8> {
+    let hash_len = ticket.cipher_suite.hash_len();
+    let identity_len = 2 + ticket.ticket.len() + 4;
+    let mut body = Vec::with_capacity(2 + identity_len + binders_len(ticket));
+    body.extend_from_slice(&(identity_len as u16).to_be_bytes());
+    body.extend_from_slice(&(ticket.ticket.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ticket.ticket);
+    body.extend_from_slice(&ticket.obfuscated_ticket_age().to_be_bytes());
+    body.extend_from_slice(&(1 + hash_len as u16).to_be_bytes());
+    body.push(hash_len as u8);
+    body.resize(body.len() + hash_len, 0);
+    body
+}
+
+/// Reads the selected_identity from a ServerHello pre_shared_key extension. We only ever
+/// offer one identity, so anything but 0 is an error.
+pub(crate) fn check_selected_identity(extensions: &[codec::RawExtension]) -> anyhow::Result<bool> {
+    match codec::find_extension(extensions, TlsExtensionType::PreSharedKey) {
+        Some(&[0, 0]) => Ok(true),
+        Some(&[_, _]) => Err(alert::fatal(
+            TlsAlertDescription::IllegalParameter,
+            "server selected a PSK identity we did not offer",
+        )),
+        Some(_) => Err(alert::fatal(
+            TlsAlertDescription::DecodeError,
+            "malformed ServerHello pre_shared_key",
+        )),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HelloRandom;
+    use crate::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    use crate::client_config::ClientConfig;
+    use crate::enc_dec;
+    use crate::key_schedule::HandshakeKeySchedule;
+    use crate::test_server::{application_key_schedule, handshake_message, hello_retry_request};
+    use crate::trust_store::TrustStore;
+    use ring::digest::{SHA256, digest};
+    use ring::test::rand::FixedByteRandom;
+    use tls_parser::NamedGroup;
+
+    // Values from the simple 1-RTT handshake (RFC 8448, section 3) and the resumed handshake
+    // that uses its ticket (section 4).
+    const RESUMPTION_MASTER_SECRET: [u8; 32] = [
+        0x7D, 0xF2, 0x35, 0xF2, 0x03, 0x1D, 0x2A, 0x05, 0x12, 0x87, 0xD0, 0x2B, 0x02, 0x41, 0xB0,
+        0xBF, 0xDA, 0xF8, 0x6C, 0xC8, 0x56, 0x23, 0x1F, 0x2D, 0x5A, 0xBA, 0x46, 0xC4, 0x34, 0xEC,
+        0x19, 0x6C,
+    ];
+    const PSK: [u8; 32] = [
+        0x4E, 0xCD, 0x0E, 0xB6, 0xEC, 0x3B, 0x4D, 0x87, 0xF5, 0xD6, 0x02, 0x8F, 0x92, 0x2C, 0xA4,
+        0xC5, 0x85, 0x1A, 0x27, 0x7F, 0xD4, 0x13, 0x11, 0xC9, 0xE6, 0x2D, 0x2C, 0x94, 0x92, 0xE1,
+        0xC4, 0xF3,
+    ];
+    const TICKET_AGE_ADD: u32 = 0xFAD6AAC5;
+    const BINDER_KEY: [u8; 32] = [
+        0x69, 0xFE, 0x13, 0x1A, 0x3B, 0xBA, 0xD5, 0xD6, 0x3C, 0x64, 0xEE, 0xBC, 0xC3, 0x0E, 0x39,
+        0x5B, 0x9D, 0x81, 0x07, 0x72, 0x6A, 0x13, 0xD0, 0x74, 0xE3, 0x89, 0xDB, 0xC8, 0xA4, 0xE4,
+        0x72, 0x56,
+    ];
+    const TRUNCATED_CLIENT_HELLO_HASH: [u8; 32] = [
+        0x63, 0x22, 0x4B, 0x2E, 0x45, 0x73, 0xF2, 0xD3, 0x45, 0x4C, 0xA8, 0x4B, 0x9D, 0x00, 0x9A,
+        0x04, 0xF6, 0xBE, 0x9E, 0x05, 0x71, 0x1A, 0x83, 0x96, 0x47, 0x3A, 0xEF, 0xA0, 0x1E, 0x92,
+        0x4A, 0x14,
+    ];
+    const BINDER: [u8; 32] = [
+        0x3A, 0xDD, 0x4F, 0xB2, 0xD8, 0xFD, 0xF8, 0x22, 0xA0, 0xCA, 0x3C, 0xF7, 0x67, 0x8E, 0xF5,
+        0xE8, 0x8D, 0xAE, 0x99, 0x01, 0x41, 0xC5, 0x92, 0x4D, 0x57, 0xBB, 0x6F, 0xA3, 0x1B, 0x9E,
+        0x5F, 0x9D,
+    ];
+
+    /// The NewSessionTicket of RFC 8448, section 3. The ticket itself is opaque to the
+    /// client, so a placeholder of the same length stands in for it.
+    fn new_session_ticket() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&30u32.to_be_bytes());
+        body.extend_from_slice(&TICKET_AGE_ADD.to_be_bytes());
+        body.extend_from_slice(&[2, 0, 0]);
+        body.extend_from_slice(&178u16.to_be_bytes());
+        body.extend_from_slice(&[0x2C; 178]);
+        body.extend_from_slice(&[0, 8, 0, 42, 0, 4, 0, 0, 4, 0]);
+        handshake_message(NEW_SESSION_TICKET, &body)
+    }
+
+    fn ticket() -> SessionTicket {
+        SessionTicket {
+            cipher_suite: &TLS13_AES_128_GCM_SHA256,
+            ticket: vec![0x2C; 178],
+            psk: PSK.to_vec(),
+            max_early_data_size: 1024,
+            alpn_protocol: None,
+            ticket_age_add: TICKET_AGE_ADD,
+            lifetime: Duration::from_secs(30),
+            received_at: Instant::now(),
+        }
+    }
+
+    /// Sends a ClientHello that offers `ticket`.
+    fn offer_ticket(
+        config: &ClientConfig,
+        ticket: &SessionTicket,
+        rng: &FixedByteRandom,
+    ) -> (HandshakeKeySchedule, HelloRandom, Vec<u8>) {
+        let mut key_schedule =
+            HandshakeKeySchedule::new(config.key_share_groups().unwrap(), rng).unwrap();
+        key_schedule.offer_psk(ticket);
+        let hello_random = HelloRandom::generate(rng, true).unwrap();
+        let mut out = Vec::new();
+        crate::send_client_hello(
+            &mut out,
+            &mut key_schedule,
+            config,
+            &hello_random,
+            Some(ticket),
+            false,
+            None,
+        )
+        .unwrap();
+        (key_schedule, hello_random, out)
+    }
+
+    /// Splits the handshake message of a ClientHello record before the binders list, which
+    /// must hold a single binder.
+    fn split_binders<'a>(record: &'a [u8], ticket: &SessionTicket) -> (&'a [u8], &'a [u8]) {
+        let (truncated, binders) = record[5..].split_at(record.len() - 5 - binders_len(ticket));
+        assert_eq!(binders[..3], [0, 33, 32]);
+        (truncated, &binders[3..])
+    }
+
+    fn first_record(buf: &[u8]) -> (&[u8], &[u8]) {
+        buf.split_at(5 + u16::from_be_bytes([buf[3], buf[4]]) as usize)
+    }
+
+    #[test]
+    fn psk_is_derived_from_resumption_master_secret_and_nonce() {
+        let key_schedule = application_key_schedule(&RESUMPTION_MASTER_SECRET);
+        let ticket = process_new_session_ticket(&new_session_ticket(), &key_schedule, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ticket.psk, PSK);
+        assert_eq!(ticket.ticket_age_add, TICKET_AGE_ADD);
+        assert_eq!(ticket.max_early_data_size, 1024);
+        assert_eq!(ticket.lifetime, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn binder_matches_rfc8448() {
+        let suite = &TLS13_AES_128_GCM_SHA256;
+        assert_eq!(HKDF::binder_key(suite, &PSK).unwrap(), BINDER_KEY);
+        let binder = enc_dec::verify_data(suite, &BINDER_KEY, &TRUNCATED_CLIENT_HELLO_HASH);
+        assert_eq!(binder.unwrap(), BINDER);
+    }
+
+    #[test]
+    fn binder_covers_client_hello_truncated_before_binders() {
+        let config = ClientConfig::new("localhost", TrustStore::empty()).unwrap();
+        let ticket = ticket();
+        let (_, _, out) = offer_ticket(&config, &ticket, &FixedByteRandom { byte: 1 });
+
+        let (truncated, binder) = split_binders(&out, &ticket);
+        let expected = enc_dec::verify_data(
+            &TLS13_AES_128_GCM_SHA256,
+            &BINDER_KEY,
+            digest(&SHA256, truncated).as_ref(),
+        );
+        assert_eq!(binder, expected.unwrap());
+    }
+
+    #[test]
+    fn binder_after_hello_retry_request_covers_both_client_hellos() {
+        let config = ClientConfig::new("localhost", TrustStore::empty()).unwrap();
+        let rng = FixedByteRandom { byte: 1 };
+        let ticket = ticket();
+        let (mut key_schedule, hello_random, mut out) = offer_ticket(&config, &ticket, &rng);
+        let hello_retry_request = hello_retry_request(
+            &hello_random.session_id.unwrap(),
+            NamedGroup::Secp256r1,
+            &[1, 2, 3, 4],
+        );
+        crate::process_hello_retry_request(
+            &mut out,
+            &mut key_schedule,
+            &config,
+            &hello_random,
+            Some(&ticket),
+            &hello_retry_request,
+            &rng,
+        )
+        .unwrap();
+
+        let (client_hello1, client_hello2) = first_record(&out);
+        let (truncated, binder) = split_binders(client_hello2, &ticket);
+        // ClientHello1 is replaced by its message_hash (RFC 8446, section 4.4.1).
+        let mut transcript = vec![254, 0, 0, 32];
+        transcript.extend_from_slice(digest(&SHA256, &client_hello1[5..]).as_ref());
+        transcript.extend_from_slice(&hello_retry_request);
+        transcript.extend_from_slice(truncated);
+        let expected = enc_dec::verify_data(
+            &TLS13_AES_128_GCM_SHA256,
+            &BINDER_KEY,
+            digest(&SHA256, &transcript).as_ref(),
+        );
+        assert_eq!(binder, expected.unwrap());
+    }
+
+    #[test]
+    fn obfuscated_ticket_age_wraps_around() {
+        assert_eq!(
+            obfuscate_ticket_age(Duration::from_millis(5000), TICKET_AGE_ADD),
+            0xFAD6BE4D
+        );
+        // 0xFAD6AAC5 + 0x0529553B overflows to 0.
+        let age = Duration::from_millis(0x0529553B);
+        assert_eq!(obfuscate_ticket_age(age, TICKET_AGE_ADD), 0);
+        assert_eq!(
+            obfuscate_ticket_age(age + Duration::from_millis(7), TICKET_AGE_ADD),
+            7
+        );
+        assert_eq!(obfuscate_ticket_age(Duration::from_millis(2), u32::MAX), 1);
+    }
+}
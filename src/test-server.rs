@@ -0,0 +1,684 @@
+use crate::alert;
+use crate::cert_verify;
+use crate::cipher_suite::{CipherSuite, TLS13_AES_128_GCM_SHA256};
+use crate::client_config::ClientConfig;
+use crate::client_connection::{ClientConnection, Event};
+use crate::client_identity::{CertificateEntry, ClientIdentity};
+use crate::codec;
+use crate::enc_dec::{self, TlsEncrypt};
+use crate::key_schedule::{ApplicationKeySchedule, HKDF, HkdfLabel};
+use crate::trust_store::TrustStore;
+use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{ECDH_P256, ECDH_P384, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{self, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair};
+use ring::test::rand::FixedByteRandom;
+use std::sync::Arc;
+use tls_parser::{
+    NamedGroup, SignatureScheme, TlsAlertDescription, TlsExtensionType, TlsRecordType,
+};
+
+const SUITE: &CipherSuite = &TLS13_AES_128_GCM_SHA256;
+
+pub(crate) fn handshake_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![msg_type];
+    msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    msg.extend_from_slice(body);
+    msg
+}
+
+pub(crate) fn plaintext_record(record_type: TlsRecordType, content: &[u8]) -> Vec<u8> {
+    let mut record = vec![record_type.0, 3, 3];
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(content);
+    record
+}
+
+/// A ServerHello for `cipher_suite` with `extensions`.
+pub(crate) fn server_hello_message(
+    cipher_suite: &CipherSuite,
+    random: &[u8],
+    session_id: &[u8],
+    extensions: &[u8],
+) -> Vec<u8> {
+    let mut body = vec![3, 3];
+    body.extend_from_slice(random);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    body.extend_from_slice(&cipher_suite.id.to_be_bytes());
+    body.push(0);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(extensions);
+    handshake_message(2, &body)
+}
+
+/// A ServerHello key_share extension.
+pub(crate) fn key_share_extension(group: NamedGroup, key_exchange: &[u8]) -> Vec<u8> {
+    let mut extension = vec![0, 51];
+    extension.extend_from_slice(&(key_exchange.len() as u16 + 4).to_be_bytes());
+    extension.extend_from_slice(&group.0.to_be_bytes());
+    extension.extend_from_slice(&(key_exchange.len() as u16).to_be_bytes());
+    extension.extend_from_slice(key_exchange);
+    extension
+}
+
+/// A HelloRetryRequest for TLS_AES_128_GCM_SHA256 that asks for a key share for `group`,
+/// with a cookie unless `cookie` is empty.
+pub(crate) fn hello_retry_request(session_id: &[u8], group: NamedGroup, cookie: &[u8]) -> Vec<u8> {
+    let mut extensions = vec![
+        0, 43, 0, 2, 3, 4, // supported_versions
+        0, 51, 0, 2, // key_share
+    ];
+    extensions.extend_from_slice(&group.0.to_be_bytes());
+    if !cookie.is_empty() {
+        extensions.extend_from_slice(&[0, 44]);
+        extensions.extend_from_slice(&(cookie.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(cookie);
+    }
+    let random = digest(&SHA256, b"HelloRetryRequest");
+    server_hello_message(SUITE, random.as_ref(), session_id, &extensions)
+}
+
+/// An application key schedule with client and server traffic secrets of all 1s and all
+/// 2s, as if just after the handshake.
+pub(crate) fn application_key_schedule(resumption_master_secret: &[u8]) -> ApplicationKeySchedule {
+    let (client_write_key, client_write_iv) = HKDF::derive_key_and_iv(SUITE, &[1; 32]).unwrap();
+    let (server_write_key, server_write_iv) = HKDF::derive_key_and_iv(SUITE, &[2; 32]).unwrap();
+    ApplicationKeySchedule {
+        cipher_suite: SUITE,
+        client_application_traffic_secret: vec![1; 32],
+        server_application_traffic_secret: vec![2; 32],
+        client_write_key,
+        client_write_iv,
+        server_write_key,
+        server_write_iv,
+        transcript_hash_context: ring::digest::Context::new(&SHA256),
+        resumption_master_secret: resumption_master_secret.to_vec(),
+        read_seq_num: 0,
+        write_seq_num: 0,
+    }
+}
+
+/// Splits off the first of `messages`.
+fn split_message(messages: &[u8]) -> (&[u8], &[u8]) {
+    messages.split_at(4 + u32::from_be_bytes([0, messages[1], messages[2], messages[3]]) as usize)
+}
+
+/// A post-handshake CertificateRequest for ecdsa_secp256r1_sha256 signatures, restricted to
+/// `certificate_authorities` unless that is empty.
+pub(crate) fn certificate_request(context: &[u8], certificate_authorities: &[&[u8]]) -> Vec<u8> {
+    let mut extensions = vec![0, 13, 0, 4, 0, 2, 4, 3];
+    if !certificate_authorities.is_empty() {
+        let mut names = Vec::new();
+        for name in certificate_authorities {
+            names.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            names.extend_from_slice(name);
+        }
+        extensions.extend_from_slice(&[0, 47]);
+        extensions.extend_from_slice(&(names.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(names.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&names);
+    }
+    let mut body = vec![context.len() as u8];
+    body.extend_from_slice(context);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+    handshake_message(13, &body)
+}
+
+/// A client identity with a self-signed ECDSA P-256 certificate, and its key.
+pub(crate) fn client_identity() -> (ClientIdentity, EcdsaKeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(Vec::new())
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    let identity = ClientIdentity::new(
+        vec![CertificateEntry::new(cert.der().to_vec())],
+        vec![SignatureScheme::ecdsa_secp256r1_sha256],
+    )
+    .unwrap();
+    let key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_ASN1_SIGNING,
+        &key.serialize_der(),
+        &SystemRandom::new(),
+    )
+    .unwrap();
+    (identity, key)
+}
+
+/// Splits the bytes the client sent into records, leaving out ChangeCipherSpec.
+pub(crate) fn records(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let (record, rest) = data.split_at(5 + u16::from_be_bytes([data[3], data[4]]) as usize);
+        if record[0] != TlsRecordType::ChangeCipherSpec.0 {
+            records.push(record);
+        }
+        data = rest;
+    }
+    records
+}
+
+/// The parts of a ClientHello the scripted server looks at.
+pub(crate) struct ClientHello {
+    pub(crate) session_id: Vec<u8>,
+    /// The groups and key_exchange of the key_share entries, in the order sent.
+    pub(crate) key_shares: Vec<(NamedGroup, Vec<u8>)>,
+    /// Whether it offers a ticket in pre_shared_key.
+    pub(crate) pre_shared_key: bool,
+    /// Whether it announces 0-RTT data with early_data.
+    pub(crate) early_data: bool,
+}
+
+impl ClientHello {
+    pub(crate) fn key_share(&self, group: NamedGroup) -> Option<&[u8]> {
+        self.key_shares
+            .iter()
+            .find(|(share_group, _)| *share_group == group)
+            .map(|(_, key_exchange)| key_exchange.as_slice())
+    }
+}
+
+/// Parses a ClientHello record.
+pub(crate) fn parse_client_hello(record: &[u8]) -> ClientHello {
+    let (_, body) = codec::split_handshake(&record[5..]).unwrap();
+    let session_id_len = body[34] as usize;
+    let session_id = body[35..35 + session_id_len].to_vec();
+    let i = &body[35 + session_id_len..];
+    let i = &i[2 + u16::from_be_bytes([i[0], i[1]]) as usize..];
+    let i = &i[1 + i[0] as usize..];
+    let extensions = codec::parse_extensions(i).unwrap();
+    let key_share = codec::find_extension(&extensions, TlsExtensionType::KeyShare).unwrap();
+    let mut shares = &key_share[2..];
+    let mut key_shares = Vec::new();
+    while !shares.is_empty() {
+        let group = NamedGroup(u16::from_be_bytes([shares[0], shares[1]]));
+        let len = u16::from_be_bytes([shares[2], shares[3]]) as usize;
+        key_shares.push((group, shares[4..4 + len].to_vec()));
+        shares = &shares[4 + len..];
+    }
+    ClientHello {
+        session_id,
+        key_shares,
+        pre_shared_key: codec::find_extension(&extensions, TlsExtensionType::PreSharedKey)
+            .is_some(),
+        early_data: codec::find_extension(&extensions, TlsExtensionType::EarlyData).is_some(),
+    }
+}
+
+/// Record protection for one direction of the scripted server's connection.
+struct TrafficKeys {
+    cipher_suite: &'static CipherSuite,
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    seq_num: u64,
+}
+
+impl TrafficKeys {
+    fn new(cipher_suite: &'static CipherSuite, traffic_secret: &[u8]) -> Self {
+        let (key, iv) = HKDF::derive_key_and_iv(cipher_suite, traffic_secret).unwrap();
+        Self {
+            cipher_suite,
+            key,
+            iv,
+            seq_num: 0,
+        }
+    }
+
+    /// Decrypts a protected record into its content type and content.
+    fn open(&mut self, record: &[u8]) -> (TlsRecordType, Vec<u8>) {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.seq_num.to_be_bytes());
+        nonce.iter_mut().zip(&self.iv).for_each(|(n, iv)| *n ^= iv);
+        self.seq_num += 1;
+        let key = LessSafeKey::new(UnboundKey::new(self.cipher_suite.aead, &self.key).unwrap());
+        let mut content = record[5..].to_vec();
+        let len = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&record[..5]),
+                &mut content,
+            )
+            .unwrap()
+            .len();
+        content.truncate(len);
+        while content.last() == Some(&0) {
+            content.pop();
+        }
+        let content_type = content.pop().unwrap();
+        (TlsRecordType(content_type), content)
+    }
+}
+
+impl TlsEncrypt for TrafficKeys {
+    fn cipher_suite(&self) -> &'static CipherSuite {
+        self.cipher_suite
+    }
+    fn get_write_seq_num_and_incr(&mut self) -> u64 {
+        self.seq_num += 1;
+        self.seq_num - 1
+    }
+    fn encryption_key(&self) -> &[u8] {
+        &self.key
+    }
+    fn encryption_iv(&self) -> &[u8] {
+        &self.iv
+    }
+}
+
+/// The server side of a full handshake with TLS_AES_128_GCM_SHA256 and an ECDSA certificate
+/// for localhost, or of one resuming from a ticket it issued. Tests decide how its messages
+/// are put into records and how the records reach the client.
+pub(crate) struct TestServer {
+    /// The group for the key exchange, X25519 unless a test picks another.
+    pub(crate) group: NamedGroup,
+    /// The cipher suite for the handshake. The transcript is always hashed with SHA-256, so
+    /// it has to be a SHA-256 suite.
+    pub(crate) cipher_suite: &'static CipherSuite,
+    /// Whether EncryptedExtensions accepts the client's 0-RTT data.
+    pub(crate) accept_early_data: bool,
+    /// The protocol EncryptedExtensions selects with ALPN, if any.
+    pub(crate) alpn_protocol: Option<Vec<u8>>,
+    ca: rcgen::Certificate,
+    cert: rcgen::Certificate,
+    key: EcdsaKeyPair,
+    transcript: ring::digest::Context,
+    client_handshake_secret: Vec<u8>,
+    server_handshake_secret: Vec<u8>,
+    /// Derive-Secret(handshake_secret, "derived", ""), the salt for the master secret.
+    master_salt: Vec<u8>,
+    client_application_secret: Vec<u8>,
+    server_application_secret: Vec<u8>,
+    /// The cipher suite and PSK of the ticket we issued. Once there is one, handshakes
+    /// resume with it.
+    ticket: Option<(&'static CipherSuite, Vec<u8>)>,
+    /// Keys for the client's 0-RTT data and EndOfEarlyData.
+    early: Option<TrafficKeys>,
+    /// Keys for what we send and for what the client sends.
+    write: TrafficKeys,
+    read: TrafficKeys,
+}
+
+impl TestServer {
+    pub(crate) fn new() -> Self {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &key.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .unwrap();
+        Self {
+            group: NamedGroup::EcdhX25519,
+            cipher_suite: SUITE,
+            accept_early_data: false,
+            alpn_protocol: None,
+            ca,
+            cert,
+            key,
+            transcript: ring::digest::Context::new(&SHA256),
+            client_handshake_secret: Vec::new(),
+            server_handshake_secret: Vec::new(),
+            master_salt: Vec::new(),
+            client_application_secret: Vec::new(),
+            server_application_secret: Vec::new(),
+            ticket: None,
+            early: None,
+            write: TrafficKeys::new(SUITE, &[]),
+            read: TrafficKeys::new(SUITE, &[]),
+        }
+    }
+
+    /// A client configuration that trusts our CA.
+    pub(crate) fn client_config(&self) -> ClientConfig {
+        let mut trust_store = TrustStore::empty();
+        trust_store.add_pem(self.ca.pem().as_bytes()).unwrap();
+        ClientConfig::new("localhost", trust_store).unwrap()
+    }
+
+    pub(crate) fn connect(&self, config: ClientConfig) -> (ClientConnection, Vec<u8>) {
+        self.connect_with_early_data(config, &[])
+    }
+
+    /// Starts a client that offers `early_data`, returning it with everything it sent.
+    pub(crate) fn connect_with_early_data(
+        &self,
+        config: ClientConfig,
+        early_data: &[u8],
+    ) -> (ClientConnection, Vec<u8>) {
+        let mut client =
+            ClientConnection::new(Arc::new(config), early_data, FixedByteRandom { byte: 1 })
+                .unwrap();
+        let outgoing = client.take_outgoing();
+        (client, outgoing)
+    }
+
+    fn transcript_hash(&self) -> ring::digest::Digest {
+        self.transcript.clone().finish()
+    }
+
+    /// A HelloRetryRequest asking for a key share for our group, as a plaintext record.
+    pub(crate) fn hello_retry_request(&mut self, client_hello: &[u8]) -> Vec<u8> {
+        let session_id = parse_client_hello(client_hello).session_id;
+        let client_hello_hash = digest(&SHA256, &client_hello[5..]);
+        self.transcript = ring::digest::Context::new(&SHA256);
+        self.transcript.update(&[254, 0, 0, 32]);
+        self.transcript.update(client_hello_hash.as_ref());
+        let msg = hello_retry_request(&session_id, self.group, &[]);
+        self.transcript.update(&msg);
+        plaintext_record(TlsRecordType::Handshake, &msg)
+    }
+
+    /// Answers the client's key share for our group with a ServerHello, as a plaintext
+    /// record, and switches to the handshake traffic keys.
+    pub(crate) fn server_hello(&mut self, client_hello: &[u8]) -> Vec<u8> {
+        let client_hello = parse_client_hello(client_hello);
+        let algorithm = match self.group {
+            NamedGroup::EcdhX25519 => &X25519,
+            NamedGroup::Secp256r1 => &ECDH_P256,
+            NamedGroup::Secp384r1 => &ECDH_P384,
+            group => panic!("no key exchange for {:?}", group),
+        };
+        let private_key = EphemeralPrivateKey::generate(algorithm, &SystemRandom::new()).unwrap();
+        let public_key = private_key.compute_public_key().unwrap();
+        let shared_secret = ring::agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(algorithm, client_hello.key_share(self.group).unwrap()),
+            |shared_secret| shared_secret.to_vec(),
+        )
+        .unwrap();
+        let mut extensions = vec![0, 43, 0, 2, 3, 4];
+        extensions.extend_from_slice(&key_share_extension(self.group, public_key.as_ref()));
+        if self.ticket.is_some() {
+            // pre_shared_key, selecting the only identity offered.
+            extensions.extend_from_slice(&[0, 41, 0, 2, 0, 0]);
+        }
+        let msg = server_hello_message(
+            self.cipher_suite,
+            &[0x5A; 32],
+            &client_hello.session_id,
+            &extensions,
+        );
+        self.transcript.update(&msg);
+
+        let psk = self.ticket.as_ref().map(|(_, psk)| psk.as_slice());
+        let salt = HKDF::derive_empty_secret(self.cipher_suite, psk).unwrap();
+        let handshake_secret = HKDF::extract(self.cipher_suite, &shared_secret, &salt);
+        let hash = self.transcript_hash();
+        let expand = |label| {
+            handshake_secret
+                .expand_label(&HkdfLabel::new(32, label, hash.as_ref()))
+                .unwrap()
+        };
+        self.client_handshake_secret = expand("c hs traffic");
+        self.server_handshake_secret = expand("s hs traffic");
+        let empty_hash = digest(&SHA256, b"");
+        self.master_salt = handshake_secret
+            .expand_label(&HkdfLabel::new(32, "derived", empty_hash.as_ref()))
+            .unwrap();
+        self.write = TrafficKeys::new(self.cipher_suite, &self.server_handshake_secret);
+        self.read = TrafficKeys::new(self.cipher_suite, &self.client_handshake_secret);
+        plaintext_record(TlsRecordType::Handshake, &msg)
+    }
+
+    /// The ClientHello the client sent after a HelloRetryRequest.
+    pub(crate) fn add_client_hello(&mut self, client_hello: &[u8]) {
+        self.transcript.update(&client_hello[5..]);
+    }
+
+    /// EncryptedExtensions, Certificate, CertificateVerify and Finished, not yet in
+    /// records; a resumed handshake has no Certificate and CertificateVerify.
+    /// `corrupt_finished` flips a bit of the Finished verify_data.
+    pub(crate) fn flight(&mut self, corrupt_finished: bool) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if self.accept_early_data {
+            extensions.extend_from_slice(&[0, 42, 0, 0]);
+        }
+        if let Some(protocol) = &self.alpn_protocol {
+            extensions.extend_from_slice(&[0, 16]);
+            extensions.extend_from_slice(&(protocol.len() as u16 + 3).to_be_bytes());
+            extensions.extend_from_slice(&(protocol.len() as u16 + 1).to_be_bytes());
+            extensions.push(protocol.len() as u8);
+            extensions.extend_from_slice(protocol);
+        }
+        let mut body = (extensions.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(&extensions);
+        let mut flight = handshake_message(8, &body);
+        self.transcript.update(&flight);
+        if self.ticket.is_none() {
+            self.add_certificate(&mut flight);
+        }
+
+        let mut verify_data = enc_dec::verify_data(
+            self.cipher_suite,
+            &self.server_handshake_secret,
+            self.transcript_hash().as_ref(),
+        )
+        .unwrap();
+        let finished = handshake_message(20, &verify_data);
+        self.transcript.update(&finished);
+        if corrupt_finished {
+            verify_data[0] ^= 1;
+        }
+        flight.extend_from_slice(&handshake_message(20, &verify_data));
+
+        let master_secret = HKDF::extract(self.cipher_suite, &[0; 32], &self.master_salt);
+        let hash = self.transcript_hash();
+        let expand = |label| {
+            master_secret
+                .expand_label(&HkdfLabel::new(32, label, hash.as_ref()))
+                .unwrap()
+        };
+        self.client_application_secret = expand("c ap traffic");
+        self.server_application_secret = expand("s ap traffic");
+        flight
+    }
+
+    /// Adds Certificate and CertificateVerify to `flight`.
+    fn add_certificate(&mut self, flight: &mut Vec<u8>) {
+        let cert = self.cert.der();
+        let mut body = vec![0];
+        body.extend_from_slice(&(cert.len() as u32 + 5).to_be_bytes()[1..]);
+        body.extend_from_slice(&(cert.len() as u32).to_be_bytes()[1..]);
+        body.extend_from_slice(cert);
+        body.extend_from_slice(&[0, 0]);
+        let certificate = handshake_message(11, &body);
+        self.transcript.update(&certificate);
+        flight.extend_from_slice(&certificate);
+
+        let signing_input = cert_verify::server_signing_input(self.transcript_hash().as_ref());
+        let signature = self.key.sign(&SystemRandom::new(), &signing_input).unwrap();
+        let mut body = vec![4, 3];
+        body.extend_from_slice(&(signature.as_ref().len() as u16).to_be_bytes());
+        body.extend_from_slice(signature.as_ref());
+        let certificate_verify = handshake_message(15, &body);
+        self.transcript.update(&certificate_verify);
+        flight.extend_from_slice(&certificate_verify);
+    }
+
+    /// Protects `content` in records of at most `max_len` bytes each.
+    pub(crate) fn seal(
+        &mut self,
+        record_type: TlsRecordType,
+        content: &[u8],
+        max_len: usize,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        for fragment in content.chunks(max_len) {
+            crate::send_encrypted_record(&mut out, &mut self.write, record_type, fragment).unwrap();
+        }
+        out
+    }
+
+    /// Checks the client's Finished, preceded by EndOfEarlyData if we accepted 0-RTT data,
+    /// and switches to the application traffic keys.
+    pub(crate) fn finish(&mut self, client_flight: &[u8]) {
+        let mut records = records(client_flight).into_iter();
+        if self.accept_early_data {
+            let early = self.early.as_mut().unwrap();
+            let (content_type, end_of_early_data) = early.open(records.next().unwrap());
+            assert_eq!(content_type, TlsRecordType::Handshake);
+            assert_eq!(end_of_early_data, crate::END_OF_EARLY_DATA);
+            self.transcript.update(&end_of_early_data);
+        }
+        assert_eq!(records.len(), 1);
+        let (content_type, finished) = self.read.open(records.next().unwrap());
+        assert_eq!(content_type, TlsRecordType::Handshake);
+        let verify_data = enc_dec::verify_data(
+            self.cipher_suite,
+            &self.client_handshake_secret,
+            self.transcript_hash().as_ref(),
+        )
+        .unwrap();
+        assert_eq!(finished, handshake_message(20, &verify_data));
+        // Post-handshake authentication builds on the transcript through this Finished.
+        self.transcript.update(&finished);
+        self.write = TrafficKeys::new(self.cipher_suite, &self.server_application_secret);
+        self.read = TrafficKeys::new(self.cipher_suite, &self.client_application_secret);
+    }
+
+    /// A NewSessionTicket that allows `max_early_data_size` bytes of 0-RTT data. Later
+    /// handshakes resume with its PSK.
+    pub(crate) fn new_session_ticket(&mut self, max_early_data_size: u32) -> Vec<u8> {
+        let master_secret = HKDF::extract(self.cipher_suite, &[0; 32], &self.master_salt);
+        let hash = self.transcript_hash();
+        let resumption_master_secret = master_secret
+            .expand_label(&HkdfLabel::new(32, "res master", hash.as_ref()))
+            .unwrap();
+        let nonce = [0];
+        let psk = HKDF::new(self.cipher_suite, &resumption_master_secret)
+            .expand_label(&HkdfLabel::new(32, "resumption", &nonce))
+            .unwrap();
+        self.ticket = Some((self.cipher_suite, psk));
+
+        // An hour's lifetime, a zero ticket_age_add, the nonce and the ticket.
+        let mut body = vec![0, 0, 0x0E, 0x10, 0, 0, 0, 0, 1];
+        body.extend_from_slice(&nonce);
+        body.extend_from_slice(&[0, 6]);
+        body.extend_from_slice(b"ticket");
+        body.extend_from_slice(&[0, 8, 0, 42, 0, 4]);
+        body.extend_from_slice(&max_early_data_size.to_be_bytes());
+        handshake_message(crate::NEW_SESSION_TICKET, &body)
+    }
+
+    /// Starts a new handshake from `client_hello`, which must offer the ticket we issued,
+    /// and takes the client_early_traffic_secret for its 0-RTT data if it announces some.
+    pub(crate) fn resume(&mut self, client_hello: &[u8]) {
+        let offer = parse_client_hello(client_hello);
+        assert!(offer.pre_shared_key);
+        let (cipher_suite, psk) = self.ticket.as_ref().unwrap();
+        self.transcript = ring::digest::Context::new(&SHA256);
+        self.transcript.update(&client_hello[5..]);
+        let hash = self.transcript_hash();
+        self.early = offer.early_data.then(|| {
+            let client_early_traffic_secret = HKDF::early_secret(cipher_suite, Some(psk))
+                .expand_label(&HkdfLabel::new(32, "c e traffic", hash.as_ref()))
+                .unwrap();
+            TrafficKeys::new(cipher_suite, &client_early_traffic_secret)
+        });
+    }
+
+    /// Decrypts a 0-RTT record the client sent with its ClientHello.
+    pub(crate) fn open_early_data(&mut self, record: &[u8]) -> (TlsRecordType, Vec<u8>) {
+        self.early.as_mut().unwrap().open(record)
+    }
+
+    /// Decrypts the single record the client sent.
+    pub(crate) fn open(&mut self, data: &[u8]) -> (TlsRecordType, Vec<u8>) {
+        let records = records(data);
+        assert_eq!(records.len(), 1);
+        let (content_type, content) = self.read.open(records[0]);
+        if content_type == TlsRecordType::Handshake && content[0] == crate::KEY_UPDATE {
+            self.client_application_secret =
+                HKDF::new(self.cipher_suite, &self.client_application_secret)
+                    .expand_label(&HkdfLabel::new(32, "traffic upd", b""))
+                    .unwrap();
+            self.read = TrafficKeys::new(self.cipher_suite, &self.client_application_secret);
+        }
+        (content_type, content)
+    }
+
+    /// Checks the client's answer to `certificate_request`, which we sent after the
+    /// handshake, and returns its Certificate message. With `client_public_key` the answer
+    /// has a CertificateVerify that verifies with it over the handshake transcript, the
+    /// CertificateRequest and the Certificate. Finished must be keyed with the client's
+    /// current application traffic secret.
+    pub(crate) fn check_certificate_answer(
+        &mut self,
+        certificate_request: &[u8],
+        answer: &[u8],
+        client_public_key: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let (content_type, messages) = self.open(answer);
+        assert_eq!(content_type, TlsRecordType::Handshake);
+        let mut transcript = self.transcript.clone();
+        transcript.update(certificate_request);
+        let (certificate, mut rest) = split_message(&messages);
+        assert_eq!(certificate[0], 11);
+        transcript.update(certificate);
+        if let Some(client_public_key) = client_public_key {
+            let (certificate_verify, after) = split_message(rest);
+            // ecdsa_secp256r1_sha256
+            assert_eq!(certificate_verify[0], 15);
+            assert_eq!(certificate_verify[4..6], [4, 3]);
+            let signing_input =
+                cert_verify::client_signing_input(transcript.clone().finish().as_ref());
+            signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, client_public_key)
+                .verify(&signing_input, &certificate_verify[8..])
+                .unwrap();
+            transcript.update(certificate_verify);
+            rest = after;
+        }
+        let verify_data = enc_dec::verify_data(
+            self.cipher_suite,
+            &self.client_application_secret,
+            transcript.finish().as_ref(),
+        )
+        .unwrap();
+        assert_eq!(rest, handshake_message(20, &verify_data));
+        certificate.to_vec()
+    }
+}
+
+pub(crate) fn received_alert(err: &anyhow::Error) -> Option<TlsAlertDescription> {
+    err.downcast_ref::<alert::ReceivedAlert>()
+        .map(|alert| alert.0)
+}
+
+/// Feeds `data` to the client `chunk_len` bytes at a time, collecting the events.
+pub(crate) fn receive_in_chunks(
+    client: &mut ClientConnection,
+    data: &[u8],
+    chunk_len: usize,
+) -> Vec<Event> {
+    data.chunks(chunk_len)
+        .flat_map(|chunk| client.receive(chunk).unwrap())
+        .collect()
+}
+
+/// Runs a handshake in which the server's encrypted flight goes out in records of at
+/// most `record_len` bytes and reaches the client `chunk_len` bytes at a time.
+pub(crate) fn handshake(record_len: usize, chunk_len: usize) -> (TestServer, ClientConnection) {
+    let mut server = TestServer::new();
+    let (mut client, client_hello) = server.connect(server.client_config());
+    server.add_client_hello(&client_hello);
+    let mut data = server.server_hello(&client_hello);
+    let flight = server.flight(false);
+    data.extend_from_slice(&server.seal(TlsRecordType::Handshake, &flight, record_len));
+
+    let events = receive_in_chunks(&mut client, &data, chunk_len);
+    assert!(matches!(events[..], [Event::HandshakeComplete]));
+    server.finish(&client.take_outgoing());
+    (server, client)
+}
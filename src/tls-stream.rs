@@ -0,0 +1,220 @@
+use crate::EarlyDataStatus;
+use crate::client_config::ClientConfig;
+use crate::client_connection::{ClientConnection, Event};
+use crate::client_identity::{ClientSigner, IdentityStore};
+use log::info;
+use ring::rand::SecureRandom;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A client TLS connection over a blocking TCP socket, driving a [`ClientConnection`]. Reads
+/// and writes carry application data; post-handshake messages and alerts are handled
+/// internally, including a CertificateRequest, which `read` answers before it returns.
+pub(crate) struct TlsStream<S> {
+    tcp_stream: TcpStream,
+    connection: ClientConnection,
+    identities: Rc<IdentityStore<S>>,
+    /// The identity whose signer CertificateVerify is signed with.
+    selected_identity: Option<usize>,
+    /// Decrypted application data not yet returned by `read`.
+    plaintext: VecDeque<u8>,
+    /// Set once the server has sent close_notify.
+    read_closed: bool,
+}
+
+impl<S: ClientSigner> TlsStream<S> {
+    /// Connects to `config.server_name` and runs the handshake, offering `early_data` as
+    /// described at [`ClientConnection::new`]. If the server asks for a certificate, it gets
+    /// the one [`IdentityStore::select`] picks from `identities`, signed for by that
+    /// identity's signer.
+    pub(crate) fn connect_with_early_data(
+        config: Arc<ClientConfig>,
+        early_data: &[u8],
+        identities: Rc<IdentityStore<S>>,
+        rng: impl SecureRandom + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        info!("connecting to {}:{}", config.server_name, config.port);
+        let tcp_stream = TcpStream::connect((config.server_name.as_str(), config.port))?;
+        let connection = ClientConnection::new(config, early_data, rng)?;
+        let mut tls_stream = Self::new(tcp_stream, connection, identities);
+        while tls_stream.connection.is_handshaking() {
+            tls_stream.write_outgoing()?;
+            let events = tls_stream.receive()?;
+            tls_stream.on_events(events)?;
+        }
+        tls_stream.write_outgoing()?;
+        Ok(tls_stream)
+    }
+
+    fn new(
+        tcp_stream: TcpStream,
+        connection: ClientConnection,
+        identities: Rc<IdentityStore<S>>,
+    ) -> Self {
+        Self {
+            tcp_stream,
+            connection,
+            identities,
+            selected_identity: None,
+            plaintext: VecDeque::new(),
+            read_closed: false,
+        }
+    }
+
+    /// See [`ClientConnection::alpn_protocol`].
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.connection.alpn_protocol()
+    }
+
+    /// See [`ClientConnection::resumed`].
+    pub(crate) fn resumed(&self) -> bool {
+        self.connection.resumed()
+    }
+
+    /// See [`ClientConnection::early_data_status`].
+    pub(crate) fn early_data_status(&self) -> EarlyDataStatus {
+        self.connection.early_data_status()
+    }
+
+    /// [`ClientConnection::send_close_notify`], then shuts down the write half of the socket.
+    pub(crate) fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.connection.send_close_notify()?;
+        self.write_outgoing()?;
+        self.tcp_stream.shutdown(std::net::Shutdown::Write)?;
+        Ok(())
+    }
+
+    fn write_outgoing(&mut self) -> anyhow::Result<()> {
+        let outgoing = self.connection.take_outgoing();
+        if !outgoing.is_empty() {
+            self.tcp_stream.write_all(&outgoing)?;
+        }
+        Ok(())
+    }
+
+    /// Passes one read from the socket to [`ClientConnection::receive`], writing what it
+    /// queued, the alert for an error included, before returning.
+    fn receive(&mut self) -> anyhow::Result<Vec<Event>> {
+        let mut buf = [0u8; 16 * 1024];
+        let n = self.tcp_stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let events = self.connection.receive(&buf[..n]);
+        self.write_outgoing()?;
+        events
+    }
+
+    /// Handles `events`, answering requests for a certificate or signature on the spot.
+    fn on_events(&mut self, events: Vec<Event>) -> anyhow::Result<()> {
+        let mut events = VecDeque::from(events);
+        while let Some(event) = events.pop_front() {
+            let more = match event {
+                Event::CertificateRequested(request) => {
+                    self.selected_identity = self.identities.select(&request);
+                    let identity = self.selected_identity.map(|i| self.identities.identity(i));
+                    self.connection.provide_certificate(identity)
+                }
+                Event::SignatureRequired(scheme, input) => {
+                    let signature = match self.selected_identity {
+                        Some(index) => self.identities.signer(index).sign(scheme, &input),
+                        None => Err(anyhow::anyhow!(
+                            "signature requested without a client identity"
+                        )),
+                    };
+                    match signature {
+                        Ok(signature) => self.connection.provide_signature(&signature),
+                        Err(e) => Err(self.connection.abort(e)),
+                    }
+                }
+                Event::HandshakeComplete | Event::UserCanceled => continue,
+                Event::ApplicationData(data) => {
+                    self.plaintext.extend(data);
+                    continue;
+                }
+                Event::PeerClosed => {
+                    self.read_closed = true;
+                    continue;
+                }
+            };
+            self.write_outgoing()?;
+            // Answered before the events still queued, as [`Event::SignatureRequired`] asks.
+            for event in more?.into_iter().rev() {
+                events.push_front(event);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: ClientSigner> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.plaintext.is_empty() && !self.read_closed {
+            let events = self.receive().map_err(std::io::Error::other)?;
+            self.on_events(events).map_err(std::io::Error::other)?;
+        }
+        self.plaintext.read(buf)
+    }
+}
+
+impl<S: ClientSigner> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.connection
+            .send_application_data(buf)
+            .and_then(|_| self.write_outgoing())
+            .map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp_stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert;
+    use crate::test_server::{certificate_request, client_identity, handshake};
+    use std::net::TcpListener;
+    use tls_parser::{SignatureScheme, TlsAlertDescription, TlsRecordType};
+
+    /// A signer whose key cannot be used.
+    struct FailingSigner;
+
+    impl ClientSigner for FailingSigner {
+        fn sign(&self, _: SignatureScheme, _: &[u8]) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("signing key unavailable")
+        }
+    }
+
+    #[test]
+    fn signer_error_sends_internal_error_alert() {
+        let (mut server, connection) = handshake(usize::MAX, usize::MAX);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut identities = IdentityStore::new();
+        identities.add(client_identity().0, FailingSigner);
+        let mut stream = TlsStream::new(tcp_stream, connection, Rc::new(identities));
+
+        let request = certificate_request(&[1], &[]);
+        let record = server.seal(TlsRecordType::Handshake, &request, usize::MAX);
+        socket.write_all(&record).unwrap();
+        let mut buf = [0u8; 16];
+        stream.read(&mut buf).unwrap_err();
+        drop(stream);
+
+        let mut alert = Vec::new();
+        socket.read_to_end(&mut alert).unwrap();
+        let (content_type, content) = server.open(&alert);
+        assert_eq!(content_type, TlsRecordType::Alert);
+        assert_eq!(
+            content,
+            alert::alert_payload(TlsAlertDescription::InternalError)
+        );
+    }
+}
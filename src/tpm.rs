@@ -1,7 +1,8 @@
+use crate::client_identity::{AsyncClientSigner, ClientSigner};
 use der::{Any, Decode};
 use der::asn1::BitString;
 use log::{debug, info};
-use ring::digest::SHA256;
+use ring::digest::{SHA256, SHA384, SHA512};
 use rsa::{BigUint, Pss, RsaPublicKey};
 use rsa::pkcs1::RsaPssParams;
 use rsa::pkcs1::der::Encode;
@@ -53,8 +54,10 @@ fn tpm_generate_csr() -> anyhow::Result<(Vec<u8>, TPMInfoSigning)>{
     let mut context =
         tss_esapi::Context::new(tcti).map_err(|e| anyhow::anyhow!("new failed: {:?}", e))?;
 
+    // No scheme on the key itself, so each signature can pick the PSS hash that the TLS
+    // signature scheme calls for.
     let public = tss_esapi::utils::create_unrestricted_signing_rsa_public(
-        RsaScheme::RsaPss(HashScheme::new(HashingAlgorithm::Sha256)),
+        RsaScheme::Null,
         RsaKeyBits::Rsa2048,
         RsaExponent::ZERO_EXPONENT,
     )
@@ -165,50 +168,126 @@ impl DynSignatureAlgorithmIdentifier for TPMInfoSigning {
 }
 impl Signer<TPMSignature> for TPMInfoSigning {
     fn try_sign(&self, msg: &[u8]) -> Result<TPMSignature, signature::Error> {
-        let digest = ring::digest::digest(&SHA256, msg);
-        let tpm_digest =
-            TPMDigest::try_from(digest.as_ref()).map_err(signature::Error::from_source)?;
+        let signature = self
+            .sign_with_scheme(tls_parser::SignatureScheme::rsa_pss_rsae_sha256, msg)
+            .map_err(signature::Error::from_source)?;
+        Ok(TPMSignature { signature })
+    }
+}
+
+impl TPMInfoSigning {
+    /// Signs `msg` with RSA-PSS over the hash that the TLS signature `scheme` names. Only the
+    /// schemes in [`crate::cert_verify::TPM_SIGNATURE_SCHEMES`] are supported.
+    pub(crate) fn sign_with_scheme(
+        &self,
+        scheme: tls_parser::SignatureScheme,
+        msg: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (hashing_algorithm, digest_algorithm, pss) = match scheme {
+            tls_parser::SignatureScheme::rsa_pss_rsae_sha256 => {
+                (HashingAlgorithm::Sha256, &SHA256, Pss::new::<sha2::Sha256>())
+            }
+            tls_parser::SignatureScheme::rsa_pss_rsae_sha384 => {
+                (HashingAlgorithm::Sha384, &SHA384, Pss::new::<sha2::Sha384>())
+            }
+            tls_parser::SignatureScheme::rsa_pss_rsae_sha512 => {
+                (HashingAlgorithm::Sha512, &SHA512, Pss::new::<sha2::Sha512>())
+            }
+            _ => anyhow::bail!("the TPM key cannot sign with {:?}", scheme),
+        };
+        let digest = ring::digest::digest(digest_algorithm, msg);
+        let tpm_digest = TPMDigest::try_from(digest.as_ref())?;
         let signature_scheme = tss_esapi::structures::SignatureScheme::RsaPss {
-            hash_scheme: HashScheme::new(HashingAlgorithm::Sha256),
+            hash_scheme: HashScheme::new(hashing_algorithm),
         };
         let hashcheck = TPMT_TK_HASHCHECK {
             tag: tss_esapi::constants::tss::TPM2_ST_HASHCHECK,
             hierarchy: tss_esapi::constants::tss::TPM2_RH_NULL,
             ..TPMT_TK_HASHCHECK::default()
         };
-        let hashcheck_ticket =
-            HashcheckTicket::try_from(hashcheck).map_err(signature::Error::from_source)?;
+        let hashcheck_ticket = HashcheckTicket::try_from(hashcheck)?;
 
         let mut tpm_context = self.tpm_context.borrow_mut();
-        let signature_from_tpm = tpm_context
-            .sign(
-                self.tpm_rsa_key_handle,
-                tpm_digest,
-                signature_scheme,
-                hashcheck_ticket,
-            )
-            .map_err(signature::Error::from_source)?;
+        let signature_from_tpm = tpm_context.sign(
+            self.tpm_rsa_key_handle,
+            tpm_digest,
+            signature_scheme,
+            hashcheck_ticket,
+        )?;
         info!(
             "signature_from_tpm: {:?} signing: {:02X?}",
             signature_from_tpm, msg
         );
         let signature_from_tpm = match signature_from_tpm {
             tss_esapi::structures::Signature::RsaPss(ref signature) => signature.signature(),
-            _ => {
-                return Err(signature::Error::from_source(anyhow::anyhow!(
-                    "expected RsaPss"
-                )));
-            }
+            _ => anyhow::bail!("expected RsaPss"),
         };
 
         let data = signature_from_tpm.value();
 
         debug!("signature_from_tpm({}): {:02X?}", data.len(), data);
-        let pss = Pss::new::<sha2::Sha256>();
-        pss.verify(&self.verifying_key(), digest.as_ref(), data)?;
+        pss.verify(&self.verifying_key(), digest.as_ref(), data)
+            .map_err(|e| anyhow::anyhow!("TPM signature does not verify: {:?}", e))?;
         info!("signature verified for msg({}) {:02X?}", msg.len(), msg);
-        Ok(TPMSignature {
-            signature: data.to_vec(),
-        })
+        Ok(data.to_vec())
+    }
+}
+
+type SignRequest = (
+    tls_parser::SignatureScheme,
+    Vec<u8>,
+    tokio::sync::oneshot::Sender<anyhow::Result<Vec<u8>>>,
+);
+
+/// Signs on a thread of its own, so async callers do not block an executor thread for the
+/// duration of a TPM operation. The TPM context is not `Send`, so the key is created on that
+/// thread and stays there.
+#[derive(Clone)]
+pub(crate) struct TpmSigningThread {
+    requests: std::sync::mpsc::Sender<SignRequest>,
+}
+
+impl TpmSigningThread {
+    /// Starts the thread and enrolls a TPM key on it, returning the client certificate.
+    pub fn spawn() -> anyhow::Result<(Vec<u8>, Self)> {
+        let (cert_sender, cert_receiver) = std::sync::mpsc::channel();
+        let (requests, request_receiver) = std::sync::mpsc::channel::<SignRequest>();
+        std::thread::spawn(move || {
+            let signer = match get_client_cert() {
+                Ok((cert, signer)) => {
+                    let _ = cert_sender.send(Ok(cert));
+                    signer
+                }
+                Err(e) => {
+                    let _ = cert_sender.send(Err(e));
+                    return;
+                }
+            };
+            for (scheme, msg, reply) in request_receiver {
+                let _ = reply.send(signer.sign_with_scheme(scheme, &msg));
+            }
+        });
+        let cert = cert_receiver.recv()??;
+        Ok((cert, Self { requests }))
+    }
+}
+
+impl ClientSigner for TPMInfoSigning {
+    fn sign(&self, scheme: tls_parser::SignatureScheme, msg: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.sign_with_scheme(scheme, msg)
+    }
+}
+
+impl AsyncClientSigner for TpmSigningThread {
+    async fn sign(
+        &self,
+        scheme: tls_parser::SignatureScheme,
+        msg: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let (reply, signature) = tokio::sync::oneshot::channel();
+        self.requests
+            .send((scheme, msg, reply))
+            .map_err(|_| anyhow::anyhow!("TPM signing thread exited"))?;
+        signature.await?
     }
 }
@@ -0,0 +1,599 @@
+use crate::alert;
+use der::oid::ObjectIdentifier;
+use der::oid::db::rfc5912::{
+    ANY_EXTENDED_KEY_USAGE, ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ID_KP_SERVER_AUTH,
+    ID_RSASSA_PSS, ID_SHA_256, ID_SHA_384, ID_SHA_512, SECP_256_R_1, SECP_384_R_1,
+    SHA_256_WITH_RSA_ENCRYPTION, SHA_384_WITH_RSA_ENCRYPTION, SHA_512_WITH_RSA_ENCRYPTION,
+};
+use der::oid::db::rfc8410::ID_ED_25519;
+use der::{Decode, Header, Reader, SliceReader, Tag};
+use log::{debug, info};
+use ring::signature;
+use rsa::pkcs1::RsaPssParams;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tls_parser::TlsAlertDescription;
+use x509_cert::certificate::Certificate;
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAltName};
+
+const MAX_CHAIN_DEPTH: usize = 8;
+
+#[derive(Debug)]
+pub(crate) enum CertificateError {
+    Malformed(der::Error),
+    Expired,
+    NotYetValid,
+    UnknownIssuer,
+    BadSignature,
+    UnsupportedSignatureAlgorithm(ObjectIdentifier),
+    CaUsedAsEndEntity,
+    NotCa,
+    PathLenExceeded,
+    InvalidKeyUsage,
+    InvalidExtendedKeyUsage,
+    NameMismatch(String),
+}
+
+impl CertificateError {
+    pub(crate) fn alert(&self) -> TlsAlertDescription {
+        match self {
+            CertificateError::Malformed(_) => TlsAlertDescription::BadCertificate,
+            CertificateError::Expired | CertificateError::NotYetValid => {
+                TlsAlertDescription::CertificateExpired
+            }
+            CertificateError::UnknownIssuer => TlsAlertDescription::UnknownCa,
+            CertificateError::UnsupportedSignatureAlgorithm(_)
+            | CertificateError::InvalidKeyUsage
+            | CertificateError::InvalidExtendedKeyUsage => {
+                TlsAlertDescription::UnsupportedCertificate
+            }
+            CertificateError::BadSignature
+            | CertificateError::CaUsedAsEndEntity
+            | CertificateError::NotCa
+            | CertificateError::PathLenExceeded
+            | CertificateError::NameMismatch(_) => TlsAlertDescription::BadCertificate,
+        }
+    }
+}
+
+impl Display for CertificateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateError::Malformed(e) => write!(f, "malformed certificate: {}", e),
+            CertificateError::Expired => write!(f, "certificate expired"),
+            CertificateError::NotYetValid => write!(f, "certificate not yet valid"),
+            CertificateError::UnknownIssuer => write!(f, "certificate issuer is not trusted"),
+            CertificateError::BadSignature => write!(f, "certificate signature is invalid"),
+            CertificateError::UnsupportedSignatureAlgorithm(oid) => {
+                write!(f, "unsupported certificate signature algorithm {}", oid)
+            }
+            CertificateError::CaUsedAsEndEntity => write!(f, "CA certificate used as end entity"),
+            CertificateError::NotCa => write!(f, "issuer is not a CA"),
+            CertificateError::PathLenExceeded => write!(f, "path length constraint exceeded"),
+            CertificateError::InvalidKeyUsage => write!(f, "certificate keyUsage not allowed"),
+            CertificateError::InvalidExtendedKeyUsage => {
+                write!(f, "certificate not valid for serverAuth")
+            }
+            CertificateError::NameMismatch(name) => {
+                write!(f, "certificate not valid for name {}", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+impl From<der::Error> for CertificateError {
+    fn from(e: der::Error) -> Self {
+        CertificateError::Malformed(e)
+    }
+}
+
+/// A certificate from the server's chain together with its TBSCertificate exactly as received,
+/// which is what the issuer signed.
+struct ReceivedCertificate<'a> {
+    cert: Certificate,
+    tbs_der: &'a [u8],
+}
+
+impl<'a> ReceivedCertificate<'a> {
+    fn from_der(der: &'a [u8]) -> Result<Self, CertificateError> {
+        let cert = Certificate::from_der(der)?;
+        let mut reader = SliceReader::new(der)?;
+        Header::decode(&mut reader)?.tag.assert_eq(Tag::Sequence)?;
+        let tbs_der = reader.tlv_bytes()?;
+        Ok(Self { cert, tbs_der })
+    }
+}
+
+/// Trust anchors used to validate the server certificate chain.
+pub(crate) struct TrustStore {
+    anchors: Vec<Certificate>,
+}
+
+impl TrustStore {
+    pub fn empty() -> Self {
+        Self {
+            anchors: Vec::new(),
+        }
+    }
+
+    /// Loads anchors from a PEM/DER file or from every certificate file in a directory.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let mut store = Self::empty();
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                let is_cert = entry_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| matches!(ext, "pem" | "crt" | "cer" | "der"));
+                if is_cert {
+                    store.add_file(&entry_path)?;
+                }
+            }
+        } else {
+            store.add_file(path)?;
+        }
+        if store.anchors.is_empty() {
+            anyhow::bail!("no trust anchors found in {}", path.display());
+        }
+        info!(
+            "loaded {} trust anchors from {}",
+            store.anchors.len(),
+            path.display()
+        );
+        Ok(store)
+    }
+
+    pub fn add_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = std::fs::read(path)?;
+        if data.starts_with(b"-----BEGIN") {
+            self.add_pem(&data)
+        } else {
+            self.add_der(&data)
+        }
+    }
+
+    pub fn add_pem(&mut self, pem: &[u8]) -> anyhow::Result<()> {
+        let certs = Certificate::load_pem_chain(pem)
+            .map_err(|e| anyhow::anyhow!("load_pem_chain failed: {:?}", e))?;
+        self.anchors.extend(certs);
+        Ok(())
+    }
+
+    pub fn add_der(&mut self, der: &[u8]) -> anyhow::Result<()> {
+        let cert = Certificate::from_der(der)
+            .map_err(|e| anyhow::anyhow!("Certificate::from_der failed: {:?}", e))?;
+        self.anchors.push(cert);
+        Ok(())
+    }
+
+    /// Builds a path from the leaf through the presented intermediates to a trust anchor.
+    pub fn verify_server_chain(
+        &self,
+        chain: &[&[u8]],
+        server_name: &str,
+    ) -> Result<(), CertificateError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let (leaf, intermediates) = chain.split_first().ok_or(CertificateError::UnknownIssuer)?;
+        let leaf = ReceivedCertificate::from_der(leaf)?;
+        let intermediates = intermediates
+            .iter()
+            .map(|der| ReceivedCertificate::from_der(der))
+            .collect::<Result<Vec<_>, _>>()?;
+        check_end_entity(&leaf.cert, server_name, now)?;
+
+        let mut current = &leaf;
+        let mut used = vec![false; intermediates.len()];
+        for depth in 0..MAX_CHAIN_DEPTH {
+            let issuer = &current.cert.tbs_certificate.issuer;
+            for anchor in self
+                .anchors
+                .iter()
+                .filter(|a| &a.tbs_certificate.subject == issuer)
+            {
+                if verify_signed_by(current, anchor).is_ok() {
+                    debug!("chain anchored at {}", anchor.tbs_certificate.subject);
+                    return Ok(());
+                }
+            }
+            let (index, intermediate) = intermediates
+                .iter()
+                .enumerate()
+                .find(|(i, c)| !used[*i] && &c.cert.tbs_certificate.subject == issuer)
+                .ok_or(CertificateError::UnknownIssuer)?;
+            check_intermediate(&intermediate.cert, depth, now)?;
+            verify_signed_by(current, &intermediate.cert)?;
+            used[index] = true;
+            current = intermediate;
+        }
+        Err(CertificateError::UnknownIssuer)
+    }
+}
+
+/// Maps a chain validation failure to the fatal alert required by RFC 8446.
+pub(crate) fn into_alert(e: CertificateError) -> anyhow::Error {
+    alert::fatal(e.alert(), e)
+}
+
+fn check_validity(cert: &Certificate, now: Duration) -> Result<(), CertificateError> {
+    let validity = &cert.tbs_certificate.validity;
+    if now < validity.not_before.to_unix_duration() {
+        return Err(CertificateError::NotYetValid);
+    }
+    if now > validity.not_after.to_unix_duration() {
+        return Err(CertificateError::Expired);
+    }
+    Ok(())
+}
+
+fn check_end_entity(
+    cert: &Certificate,
+    server_name: &str,
+    now: Duration,
+) -> Result<(), CertificateError> {
+    check_validity(cert, now)?;
+    let tbs = &cert.tbs_certificate;
+    if tbs
+        .get::<BasicConstraints>()?
+        .is_some_and(|(_, basic_constraints)| basic_constraints.ca)
+    {
+        return Err(CertificateError::CaUsedAsEndEntity);
+    }
+    if tbs
+        .get::<KeyUsage>()?
+        .is_some_and(|(_, key_usage)| !key_usage.digital_signature())
+    {
+        return Err(CertificateError::InvalidKeyUsage);
+    }
+    check_server_auth(cert)?;
+    check_server_name(cert, server_name)
+}
+
+fn check_intermediate(
+    cert: &Certificate,
+    depth: usize,
+    now: Duration,
+) -> Result<(), CertificateError> {
+    check_validity(cert, now)?;
+    let tbs = &cert.tbs_certificate;
+    match tbs.get::<BasicConstraints>()? {
+        Some((_, basic_constraints)) if basic_constraints.ca => {
+            if basic_constraints
+                .path_len_constraint
+                .is_some_and(|path_len| depth > path_len as usize)
+            {
+                return Err(CertificateError::PathLenExceeded);
+            }
+        }
+        _ => return Err(CertificateError::NotCa),
+    }
+    if tbs
+        .get::<KeyUsage>()?
+        .is_some_and(|(_, key_usage)| !key_usage.key_cert_sign())
+    {
+        return Err(CertificateError::InvalidKeyUsage);
+    }
+    check_server_auth(cert)
+}
+
+fn check_server_auth(cert: &Certificate) -> Result<(), CertificateError> {
+    let server_auth =
+        |oid: &ObjectIdentifier| *oid == ID_KP_SERVER_AUTH || *oid == ANY_EXTENDED_KEY_USAGE;
+    if cert
+        .tbs_certificate
+        .get::<ExtendedKeyUsage>()?
+        .is_some_and(|(_, eku)| !eku.0.iter().any(server_auth))
+    {
+        return Err(CertificateError::InvalidExtendedKeyUsage);
+    }
+    Ok(())
+}
+
+fn check_server_name(cert: &Certificate, server_name: &str) -> Result<(), CertificateError> {
+    let san = cert
+        .tbs_certificate
+        .get::<SubjectAltName>()?
+        .map(|(_, san)| san.0)
+        .unwrap_or_default();
+    let ip = server_name.parse::<IpAddr>().ok();
+    let matched = san.iter().any(|name| match (name, ip) {
+        (GeneralName::DnsName(dns_name), None) => dns_name_matches(dns_name.as_str(), server_name),
+        (GeneralName::IpAddress(address), Some(ip)) => match ip {
+            IpAddr::V4(v4) => address.as_bytes() == &v4.octets()[..],
+            IpAddr::V6(v6) => address.as_bytes() == &v6.octets()[..],
+        },
+        _ => false,
+    });
+    if matched {
+        Ok(())
+    } else {
+        Err(CertificateError::NameMismatch(server_name.to_string()))
+    }
+}
+
+/// Compares a SAN dNSName with the server name, allowing a wildcard in the leftmost label only.
+fn dns_name_matches(pattern: &str, server_name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.');
+    let server_name = server_name.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => server_name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+        None => pattern.eq_ignore_ascii_case(server_name),
+    }
+}
+
+fn signature_algorithm(
+    cert: &Certificate,
+    issuer: &Certificate,
+) -> Result<&'static dyn signature::VerificationAlgorithm, CertificateError> {
+    let algorithm = &cert.signature_algorithm;
+    let issuer_curve = issuer
+        .tbs_certificate
+        .subject_public_key_info
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|p| p.decode_as::<ObjectIdentifier>().ok());
+    let unsupported = || CertificateError::UnsupportedSignatureAlgorithm(algorithm.oid);
+    match algorithm.oid {
+        SHA_256_WITH_RSA_ENCRYPTION => Ok(&signature::RSA_PKCS1_2048_8192_SHA256),
+        SHA_384_WITH_RSA_ENCRYPTION => Ok(&signature::RSA_PKCS1_2048_8192_SHA384),
+        SHA_512_WITH_RSA_ENCRYPTION => Ok(&signature::RSA_PKCS1_2048_8192_SHA512),
+        ID_RSASSA_PSS => {
+            let params = algorithm
+                .parameters
+                .as_ref()
+                .ok_or_else(unsupported)?
+                .decode_as::<RsaPssParams>()?;
+            match params.hash.oid {
+                ID_SHA_256 => Ok(&signature::RSA_PSS_2048_8192_SHA256),
+                ID_SHA_384 => Ok(&signature::RSA_PSS_2048_8192_SHA384),
+                ID_SHA_512 => Ok(&signature::RSA_PSS_2048_8192_SHA512),
+                _ => Err(unsupported()),
+            }
+        }
+        ECDSA_WITH_SHA_256 => match issuer_curve {
+            Some(SECP_256_R_1) => Ok(&signature::ECDSA_P256_SHA256_ASN1),
+            Some(SECP_384_R_1) => Ok(&signature::ECDSA_P384_SHA256_ASN1),
+            _ => Err(unsupported()),
+        },
+        ECDSA_WITH_SHA_384 => match issuer_curve {
+            Some(SECP_256_R_1) => Ok(&signature::ECDSA_P256_SHA384_ASN1),
+            Some(SECP_384_R_1) => Ok(&signature::ECDSA_P384_SHA384_ASN1),
+            _ => Err(unsupported()),
+        },
+        ID_ED_25519 => Ok(&signature::ED25519),
+        _ => Err(unsupported()),
+    }
+}
+
+fn verify_signed_by(
+    cert: &ReceivedCertificate,
+    issuer: &Certificate,
+) -> Result<(), CertificateError> {
+    let algorithm = signature_algorithm(&cert.cert, issuer)?;
+    let issuer_key = issuer
+        .tbs_certificate
+        .subject_public_key_info
+        .subject_public_key
+        .raw_bytes();
+    signature::UnparsedPublicKey::new(algorithm, issuer_key)
+        .verify(cert.tbs_der, cert.cert.signature.raw_bytes())
+        .map_err(|_| CertificateError::BadSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        KeyUsagePurpose,
+    };
+
+    /// The CA of the server's `TestPKI`: the same parameters, signed with its key.
+    fn test_pki_ca() -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::from_pem(include_str!("../server/privatekey.pem")).unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Sec-PoC-CA");
+        params.distinguished_name.push(DnType::CommonName, "PoC CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::CrlSign,
+        ];
+        let cert = params.self_signed(&key).unwrap();
+        (cert, key)
+    }
+
+    /// A server certificate like `TestPKI`'s for `names`.
+    fn server_params(names: &[&str]) -> CertificateParams {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let mut params = CertificateParams::new(names).unwrap();
+        params.is_ca = IsCa::NoCa;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params
+    }
+
+    fn intermediate_params(name: &str, constraints: BasicConstraints) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(constraints);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+        params
+    }
+
+    fn trust_store(anchor: &rcgen::Certificate) -> TrustStore {
+        let mut store = TrustStore::empty();
+        store.add_pem(anchor.pem().as_bytes()).unwrap();
+        store
+    }
+
+    fn verify(
+        store: &TrustStore,
+        chain: &[&rcgen::Certificate],
+        server_name: &str,
+    ) -> Result<(), CertificateError> {
+        let chain = chain
+            .iter()
+            .map(|cert| cert.der().as_ref())
+            .collect::<Vec<_>>();
+        store.verify_server_chain(&chain, server_name)
+    }
+
+    #[test]
+    fn accepts_test_pki_server_certificate() {
+        let (ca, ca_key) = test_pki_ca();
+        // TestPKI signs its server certificate for its own key.
+        let leaf = server_params(&["localhost"])
+            .signed_by(&ca_key, &ca, &ca_key)
+            .unwrap();
+        verify(&trust_store(&ca), &[&leaf], "localhost").unwrap();
+    }
+
+    #[test]
+    fn accepts_chain_through_intermediate() {
+        let (ca, ca_key) = test_pki_ca();
+        let intermediate_key = KeyPair::generate().unwrap();
+        let intermediate =
+            intermediate_params("PoC Intermediate", BasicConstraints::Constrained(0))
+                .signed_by(&intermediate_key, &ca, &ca_key)
+                .unwrap();
+        let leaf = server_params(&["localhost"])
+            .signed_by(
+                &KeyPair::generate().unwrap(),
+                &intermediate,
+                &intermediate_key,
+            )
+            .unwrap();
+        verify(&trust_store(&ca), &[&leaf, &intermediate], "localhost").unwrap();
+    }
+
+    #[test]
+    fn rejects_expired_certificate() {
+        let (ca, ca_key) = test_pki_ca();
+        let mut params = server_params(&["localhost"]);
+        params.not_before = rcgen::date_time_ymd(1999, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2000, 1, 1);
+        let leaf = params.signed_by(&ca_key, &ca, &ca_key).unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf], "localhost").unwrap_err();
+        assert!(matches!(err, CertificateError::Expired), "{:?}", err);
+        assert_eq!(err.alert(), TlsAlertDescription::CertificateExpired);
+    }
+
+    #[test]
+    fn rejects_certificate_not_yet_valid() {
+        let (ca, ca_key) = test_pki_ca();
+        let mut params = server_params(&["localhost"]);
+        params.not_before = rcgen::date_time_ymd(3000, 1, 1);
+        let leaf = params.signed_by(&ca_key, &ca, &ca_key).unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf], "localhost").unwrap_err();
+        assert!(matches!(err, CertificateError::NotYetValid), "{:?}", err);
+        assert_eq!(err.alert(), TlsAlertDescription::CertificateExpired);
+    }
+
+    #[test]
+    fn rejects_unknown_issuer() {
+        let (ca, _) = test_pki_ca();
+        // Another CA with the TestPKI CA's name but a different key.
+        let mut other_params = CertificateParams::new(Vec::new()).unwrap();
+        other_params.distinguished_name = ca.params().distinguished_name.clone();
+        other_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let other_key = KeyPair::generate().unwrap();
+        let other_ca = other_params.self_signed(&other_key).unwrap();
+        let leaf = server_params(&["localhost"])
+            .signed_by(&KeyPair::generate().unwrap(), &other_ca, &other_key)
+            .unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf], "localhost").unwrap_err();
+        assert!(matches!(err, CertificateError::UnknownIssuer), "{:?}", err);
+        assert_eq!(err.alert(), TlsAlertDescription::UnknownCa);
+    }
+
+    #[test]
+    fn rejects_name_mismatch() {
+        let (ca, ca_key) = test_pki_ca();
+        let leaf = server_params(&["localhost"])
+            .signed_by(&ca_key, &ca, &ca_key)
+            .unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf], "example.com").unwrap_err();
+        assert!(
+            matches!(&err, CertificateError::NameMismatch(name) if name == "example.com"),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.alert(), TlsAlertDescription::BadCertificate);
+    }
+
+    #[test]
+    fn matches_wildcard_in_leftmost_label_only() {
+        let (ca, ca_key) = test_pki_ca();
+        let leaf = server_params(&["*.poc.test"])
+            .signed_by(&ca_key, &ca, &ca_key)
+            .unwrap();
+        let store = trust_store(&ca);
+        verify(&store, &[&leaf], "api.poc.test").unwrap();
+        verify(&store, &[&leaf], "API.Poc.Test").unwrap();
+        for server_name in ["poc.test", "a.api.poc.test", ".poc.test"] {
+            let err = verify(&store, &[&leaf], server_name).unwrap_err();
+            assert!(
+                matches!(err, CertificateError::NameMismatch(_)),
+                "{}: {:?}",
+                server_name,
+                err
+            );
+            assert_eq!(err.alert(), TlsAlertDescription::BadCertificate);
+        }
+    }
+
+    #[test]
+    fn rejects_ca_certificate_as_leaf() {
+        let (ca, ca_key) = test_pki_ca();
+        let mut params = server_params(&["localhost"]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let leaf = params.signed_by(&ca_key, &ca, &ca_key).unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf], "localhost").unwrap_err();
+        assert!(
+            matches!(err, CertificateError::CaUsedAsEndEntity),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.alert(), TlsAlertDescription::BadCertificate);
+    }
+
+    #[test]
+    fn rejects_path_len_violation() {
+        let (ca, ca_key) = test_pki_ca();
+        // pathLenConstraint 0 allows no further CA below the first intermediate.
+        let first_key = KeyPair::generate().unwrap();
+        let first = intermediate_params("PoC Intermediate 1", BasicConstraints::Constrained(0))
+            .signed_by(&first_key, &ca, &ca_key)
+            .unwrap();
+        let second_key = KeyPair::generate().unwrap();
+        let second = intermediate_params("PoC Intermediate 2", BasicConstraints::Unconstrained)
+            .signed_by(&second_key, &first, &first_key)
+            .unwrap();
+        let leaf = server_params(&["localhost"])
+            .signed_by(&KeyPair::generate().unwrap(), &second, &second_key)
+            .unwrap();
+        let err = verify(&trust_store(&ca), &[&leaf, &second, &first], "localhost").unwrap_err();
+        assert!(
+            matches!(err, CertificateError::PathLenExceeded),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.alert(), TlsAlertDescription::BadCertificate);
+    }
+}